@@ -1,8 +1,9 @@
 use inps::{Node, NodeConfig};
 
 fn main() {
-    let node = Node::new(NodeConfig {
+    let node = Node::new(&NodeConfig {
         name: "hello".to_string(),
         max_nodes: 20,
+        handle_signals: false,
     });
 }
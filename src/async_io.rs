@@ -0,0 +1,266 @@
+use crate::errors::{ErrorKind, SocketError};
+use crate::event::EventFd;
+use crate::node::{not_caught_up, Node, Subscription};
+use crate::publisher::Publisher;
+use crate::shared_segment::Sample;
+use futures_core::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+impl Publisher<Sample> {
+    // Best effort samples overwrite the oldest slot. Reliable ones wait up to
+    // max_blocking for subscribers to catch up, on tokio's blocking threads
+    // rather than the worker's. Must be called within a tokio runtime.
+    pub async fn publish(&self, head: &[u8], body: &[u8]) -> Result<(), SocketError> {
+        let mut deadline: Option<Instant> = None;
+        loop {
+            let blocked = match self.try_publish_bytes(head, body)? {
+                Some(blocked) => blocked,
+                None => {
+                    return Ok(());
+                }
+            };
+            let deadline = *deadline.get_or_insert(Instant::now() + blocked.max_blocking);
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(not_caught_up(self.topic()));
+            }
+
+            let timeout = deadline - now;
+            let wait = move || blocked.room.wait_timeout(blocked.seen, Some(timeout));
+            match tokio::task::spawn_blocking(wait).await {
+                Ok(waited) => waited?,
+                Err(err) => {
+                    return Err(SocketError::new(format!(
+                        "Failed to wait on subscribers of {}: {}",
+                        self.topic(),
+                        err
+                    )));
+                }
+            }
+        }
+    }
+}
+
+impl Node {
+    // topic has to have been announced already
+    pub fn publisher(&self, topic: &str) -> Result<Publisher, SocketError> {
+        if !self.state.lock().unwrap().is_announced(topic) {
+            return Err(SocketError::new(format!(
+                "Publisher for {} without announcing it",
+                topic
+            )));
+        }
+        return Ok(Publisher::raw(topic, std::sync::Arc::downgrade(&self.state)));
+    }
+}
+
+impl Subscription {
+    // Waits for the next sample. Must be called within a tokio runtime. Once
+    // the node has shut down and what arrived before has been taken, fails
+    // with ErrorKind::Shutdown.
+    pub async fn recv(&mut self) -> Result<Sample, SocketError> {
+        return std::future::poll_fn(|cx| self.poll_recv(cx)).await;
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<Sample, SocketError>> {
+        return self.poll_sample(cx).map(|sample| match sample {
+            Some(sample) => sample,
+            None => Err(SocketError::with_kind(
+                ErrorKind::Shutdown,
+                "Receive after the node shut down".to_string(),
+            )),
+        });
+    }
+
+    // the next sample, None once the node has shut down and there are none
+    // left
+    fn poll_sample(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Sample, SocketError>>> {
+        loop {
+            if let Some(sample) = self.take() {
+                return Poll::Ready(Some(Ok(sample)));
+            }
+
+            if self.ready.is_none() {
+                match self.event().dup().and_then(register) {
+                    Ok(ready) => {
+                        self.ready = Some(ready);
+                    }
+                    Err(err) => {
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                }
+            }
+            if self.stopped.is_none() {
+                match self.node_stopped() {
+                    Ok(Some(event)) => match register(event) {
+                        Ok(stopped) => {
+                            self.stopped = Some(stopped);
+                        }
+                        Err(err) => {
+                            return Poll::Ready(Some(Err(err)));
+                        }
+                    },
+                    Ok(None) => {
+                        return Poll::Ready(None);
+                    }
+                    Err(err) => {
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                }
+            }
+
+            // never reset, so it stays ready once the node has stopped, and
+            // nothing is handed to us after what's already there
+            let stopped = self.stopped.as_ref().unwrap().poll_read_ready(cx);
+            if stopped.is_ready() {
+                return Poll::Ready(self.take().map(Ok));
+            }
+
+            let mut guard = match self.ready.as_ref().unwrap().poll_read_ready(cx) {
+                Poll::Pending => {
+                    return Poll::Pending;
+                }
+                Poll::Ready(Err(err)) => {
+                    return Poll::Ready(Some(Err(SocketError::new(format!(
+                        "Failed to poll subscription: {}",
+                        err
+                    )))));
+                }
+                Poll::Ready(Ok(guard)) => guard,
+            };
+
+            // reset the count, everything published up to here is taken on
+            // the next time around
+            match guard.get_inner().try_decr() {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    guard.clear_ready();
+                }
+                Err(err) => {
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+        }
+    }
+}
+
+// registers event with the tokio reactor of the runtime we're called within
+fn register(event: EventFd) -> Result<AsyncFd<EventFd>, SocketError> {
+    match AsyncFd::with_interest(event, Interest::READABLE) {
+        Ok(ready) => {
+            return Ok(ready);
+        }
+        Err(err) => {
+            return Err(SocketError::new(format!(
+                "Failed to register with runtime: {}",
+                err
+            )));
+        }
+    }
+}
+
+// ends once the node has shut down, see Subscription::recv()
+impl Stream for Subscription {
+    type Item = Result<Sample, SocketError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        return self.get_mut().poll_sample(cx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::{AnnounceConfig, NodeConfig, SubscriptionConfig};
+    use crate::qos::{History, QosProfile, Reliability};
+    use std::time::Duration;
+
+    #[test]
+    fn reliable_publish_leaves_the_runtime_running() {
+        let node = Node::new(&NodeConfig {
+            name: "async_reliable".to_string(),
+            max_nodes: 16,
+            handle_signals: false,
+        })
+        .unwrap();
+        let qos = QosProfile {
+            history: History::KeepLast(2),
+            reliability: Reliability::Reliable {
+                max_blocking: Duration::from_secs(10),
+            },
+            ..Default::default()
+        };
+        let config = AnnounceConfig {
+            qos: qos,
+            ..Default::default()
+        };
+        node.announce_with("/async_reliable", "", "X", b"", &config)
+            .unwrap();
+        let config = SubscriptionConfig {
+            qos: qos,
+            ..Default::default()
+        };
+        let sub = node.subscription_with("/async_reliable", &config).unwrap();
+        let publisher = node.publisher("/async_reliable").unwrap();
+
+        // a single worker, which the waiting publish would otherwise hold up
+        // until it gives up
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        runtime.block_on(async move {
+            publisher.publish(b"", b"1").await.unwrap();
+            publisher.publish(b"", b"2").await.unwrap();
+            let published = tokio::spawn(async move {
+                return publisher.publish(b"", b"3").await;
+            });
+            tokio::task::yield_now().await;
+            assert_eq!(sub.take().unwrap().body, b"1");
+            published.await.unwrap().unwrap();
+        });
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    async fn next(sub: &mut Subscription) -> Option<Result<Sample, SocketError>> {
+        return std::future::poll_fn(|cx| Pin::new(&mut *sub).poll_next(cx)).await;
+    }
+
+    #[test]
+    fn receiving_ends_with_the_node() {
+        let mut node = Node::new(&NodeConfig {
+            name: "async_shutdown".to_string(),
+            max_nodes: 16,
+            handle_signals: false,
+        })
+        .unwrap();
+        node.announce("/async_shutdown", "", "X", b"").unwrap();
+        let mut waiting = node.subscription("/async_shutdown").unwrap();
+        let mut streaming = node.subscription("/async_shutdown").unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let received = tokio::spawn(async move {
+                return (waiting.recv().await, waiting.recv().await);
+            });
+            tokio::task::yield_now().await;
+            node.publish("/async_shutdown", b"", b"last").unwrap();
+            node.shutdown().unwrap();
+
+            // what arrived before shutting down is still there, then it ends
+            let (last, after) = received.await.unwrap();
+            assert_eq!(last.unwrap().body, b"last");
+            assert_eq!(after.err().unwrap().kind(), ErrorKind::Shutdown);
+            assert_eq!(next(&mut streaming).await.unwrap().unwrap().body, b"last");
+            assert!(next(&mut streaming).await.is_none());
+        });
+    }
+}
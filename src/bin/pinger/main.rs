@@ -5,6 +5,7 @@ fn main() {
     let node = Node::new(&NodeConfig {
         name: "hello".to_string(),
         max_nodes: 20,
+        handle_signals: false,
     })
     .unwrap();
 
@@ -12,11 +13,11 @@ fn main() {
         println!("head: {:?}, body: {:?}", header, body);
     };
 
-    node.announce("/ping", "", "", b"");
-    node.subscribe("/ping", &cb);
+    node.announce("/ping", "", "", b"").unwrap();
+    node.subscribe("/ping", cb).unwrap();
 
     loop {
         thread::sleep(time::Duration::from_millis(250));
-        node.publish("/ping", b"hello_head", b"hello_body");
+        node.publish("/ping", b"hello_head", b"hello_body").unwrap();
     }
 }
@@ -0,0 +1,150 @@
+use crate::errors::SocketError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// Turns typed messages into sample bodies and back
+pub trait Codec {
+    // Part of the announced type name, so a subscriber using a different
+    // codec than the publisher is rejected like any other type mismatch
+    const NAME: &'static str;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, SocketError>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, SocketError>;
+}
+
+#[cfg(feature = "bincode")]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    const NAME: &'static str = "bincode";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, SocketError> {
+        match bincode::serialize(value) {
+            Ok(bytes) => {
+                return Ok(bytes);
+            }
+            Err(err) => {
+                return Err(SocketError::new(format!("Failed to encode bincode: {}", err)));
+            }
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, SocketError> {
+        match bincode::deserialize(bytes) {
+            Ok(value) => {
+                return Ok(value);
+            }
+            Err(err) => {
+                return Err(SocketError::new(format!("Failed to decode bincode: {}", err)));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "cbor")]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    const NAME: &'static str = "cbor";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, SocketError> {
+        let mut bytes: Vec<u8> = Vec::new();
+        match ciborium::into_writer(value, &mut bytes) {
+            Ok(()) => {
+                return Ok(bytes);
+            }
+            Err(err) => {
+                return Err(SocketError::new(format!("Failed to encode cbor: {}", err)));
+            }
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, SocketError> {
+        match ciborium::from_reader(bytes) {
+            Ok(value) => {
+                return Ok(value);
+            }
+            Err(err) => {
+                return Err(SocketError::new(format!("Failed to decode cbor: {}", err)));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    const NAME: &'static str = "json";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, SocketError> {
+        match serde_json::to_vec(value) {
+            Ok(bytes) => {
+                return Ok(bytes);
+            }
+            Err(err) => {
+                return Err(SocketError::new(format!("Failed to encode json: {}", err)));
+            }
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, SocketError> {
+        match serde_json::from_slice(bytes) {
+            Ok(value) => {
+                return Ok(value);
+            }
+            Err(err) => {
+                return Err(SocketError::new(format!("Failed to decode json: {}", err)));
+            }
+        }
+    }
+}
+
+// codec used by advertise() and subscribe_typed(), the first enabled of
+// bincode, cbor and json
+#[cfg(feature = "bincode")]
+pub type DefaultCodec = Bincode;
+#[cfg(all(feature = "cbor", not(feature = "bincode")))]
+pub type DefaultCodec = Cbor;
+#[cfg(all(feature = "json", not(any(feature = "bincode", feature = "cbor"))))]
+pub type DefaultCodec = Json;
+
+#[cfg(all(test, any(feature = "bincode", feature = "cbor", feature = "json")))]
+mod tests {
+    use super::*;
+
+    type Message = (u32, String, Vec<f64>);
+
+    fn message() -> Message {
+        return (7, "seven".to_string(), vec![0.5, 7.0]);
+    }
+
+    // what encodes decodes the same, and what's not a message fails
+    fn round_trip<C: Codec>() {
+        let bytes = C::encode(&message()).unwrap();
+        assert_eq!(C::decode::<Message>(&bytes).unwrap(), message());
+        assert!(C::decode::<Message>(&[0xff; 3]).is_err());
+        assert!(C::decode::<String>(&C::encode(&7u32).unwrap()).is_err());
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_round_trip() {
+        round_trip::<Bincode>();
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trip() {
+        round_trip::<Cbor>();
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_round_trip() {
+        round_trip::<Json>();
+    }
+}
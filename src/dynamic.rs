@@ -0,0 +1,836 @@
+use crate::errors::SocketError;
+use crate::node::{Node, SubscriptionConfig};
+use crate::schema;
+use crate::shared_segment::Sample;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{FieldDescriptorProto, FileDescriptorSet};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_START_GROUP: u64 = 3;
+const WIRE_END_GROUP: u64 = 4;
+const WIRE_FIXED32: u64 = 5;
+
+// how deeply messages and groups may nest, so a self-referential definition
+// can't overflow the stack, the same limit as protobuf's own decoders
+const MAX_DEPTH: usize = 100;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    I32(i32),
+    I64(i64),
+    U32(u32),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    // name is None for numbers the enum doesn't define
+    Enum { number: i32, name: Option<String> },
+    Message(DynamicMessage),
+    // every occurrence of a repeated field, maps are lists of key/value entries
+    List(Vec<Value>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub number: u32,
+    pub value: Value,
+}
+
+// A message decoded without compile time types. Only fields that were on the
+// wire are present, in the order the definition declares them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicMessage {
+    pub type_name: String,
+    pub fields: Vec<Field>,
+}
+
+// Decodes bodies of one message type from the definitions it was announced with
+pub struct DynamicDecoder {
+    set: FileDescriptorSet,
+    type_name: String,
+}
+
+impl Value {
+    // numbers, bools and enums as f64, e.g. for plotting
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+            Value::I32(value) => Some(*value as f64),
+            Value::I64(value) => Some(*value as f64),
+            Value::U32(value) => Some(*value as f64),
+            Value::U64(value) => Some(*value as f64),
+            Value::F32(value) => Some(*value as f64),
+            Value::F64(value) => Some(*value),
+            Value::Enum { number, .. } => Some(*number as f64),
+            _ => None,
+        }
+    }
+}
+
+impl DynamicMessage {
+    // Looks up a field by name, with dots to reach into nested messages, e.g.
+    // "pose.position.x"
+    pub fn get(&self, path: &str) -> Option<&Value> {
+        let (name, rest) = match path.split_once('.') {
+            Some((name, rest)) => (name, Some(rest)),
+            None => (path, None),
+        };
+        let value = &self.fields.iter().find(|field| field.name == name)?.value;
+        match (rest, value) {
+            (None, _) => {
+                return Some(value);
+            }
+            (Some(rest), Value::Message(message)) => {
+                return message.get(rest);
+            }
+            _ => {
+                return None;
+            }
+        }
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        for field in self.fields.iter() {
+            match &field.value {
+                Value::List(values) => {
+                    for value in values.iter() {
+                        write_field(f, indent, &field.name, value)?;
+                    }
+                }
+                value => {
+                    write_field(f, indent, &field.name, value)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+}
+
+fn write_field(f: &mut fmt::Formatter, indent: usize, name: &str, value: &Value) -> fmt::Result {
+    match value {
+        Value::Message(message) => {
+            writeln!(f, "{:indent$}{} {{", "", name, indent = indent)?;
+            message.write(f, indent + 2)?;
+            writeln!(f, "{:indent$}}}", "", indent = indent)
+        }
+        value => writeln!(f, "{:indent$}{}: {}", "", name, value, indent = indent),
+    }
+}
+
+// protobuf text format, more or less
+impl fmt::Display for DynamicMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::I32(value) => write!(f, "{}", value),
+            Value::I64(value) => write!(f, "{}", value),
+            Value::U32(value) => write!(f, "{}", value),
+            Value::U64(value) => write!(f, "{}", value),
+            Value::F32(value) => write!(f, "{}", value),
+            Value::F64(value) => write!(f, "{}", value),
+            Value::String(value) => write!(f, "{:?}", value),
+            Value::Bytes(value) => write!(f, "\"{}\"", value.escape_ascii()),
+            Value::Enum { number, name } => match name {
+                Some(name) => write!(f, "{}", name),
+                None => write!(f, "{}", number),
+            },
+            Value::Message(message) => write!(
+                f,
+                "{{ {} }}",
+                message.to_string().trim_end().replace('\n', " ")
+            ),
+            Value::List(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+fn malformed(type_name: &str, what: &str) -> SocketError {
+    return SocketError::new(format!("Malformed {}: {}", type_name, what));
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    type_name: &'a str,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        return self.bytes.is_empty();
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SocketError> {
+        if n > self.bytes.len() {
+            return Err(malformed(self.type_name, "truncated"));
+        }
+        let (out, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        return Ok(out);
+    }
+
+    fn varint(&mut self) -> Result<u64, SocketError> {
+        let mut value: u64 = 0;
+        for i in 0..10 {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        return Err(malformed(self.type_name, "varint longer than 10 bytes"));
+    }
+
+    fn fixed32(&mut self) -> Result<u32, SocketError> {
+        return Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()));
+    }
+
+    fn fixed64(&mut self) -> Result<u64, SocketError> {
+        return Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()));
+    }
+
+    fn len_delimited(&mut self) -> Result<&'a [u8], SocketError> {
+        let len = self.varint()?;
+        return self.take(len as usize);
+    }
+
+    // skips a field we have no definition for, depth groups deep
+    fn skip(&mut self, number: u64, wire_type: u64, depth: usize) -> Result<(), SocketError> {
+        if depth > MAX_DEPTH {
+            return Err(malformed(self.type_name, "nested too deeply"));
+        }
+        match wire_type {
+            WIRE_VARINT => {
+                self.varint()?;
+            }
+            WIRE_FIXED64 => {
+                self.take(8)?;
+            }
+            WIRE_LEN => {
+                self.len_delimited()?;
+            }
+            WIRE_FIXED32 => {
+                self.take(4)?;
+            }
+            WIRE_START_GROUP => loop {
+                let key = self.varint()?;
+                if key & 7 == WIRE_END_GROUP {
+                    if key >> 3 != number {
+                        return Err(malformed(self.type_name, "mismatched end of group"));
+                    }
+                    break;
+                }
+                self.skip(key >> 3, key & 7, depth + 1)?;
+            },
+            _ => {
+                return Err(malformed(self.type_name, "unknown wire type"));
+            }
+        }
+        return Ok(());
+    }
+}
+
+fn wire_type_of(field_type: Type) -> u64 {
+    match field_type {
+        Type::Double | Type::Fixed64 | Type::Sfixed64 => WIRE_FIXED64,
+        Type::Float | Type::Fixed32 | Type::Sfixed32 => WIRE_FIXED32,
+        Type::String | Type::Bytes | Type::Message => WIRE_LEN,
+        Type::Group => WIRE_START_GROUP,
+        _ => WIRE_VARINT,
+    }
+}
+
+impl DynamicDecoder {
+    // proto_defs is a serialized FileDescriptorSet that has to define type_name
+    pub fn new(proto_defs: &[u8], type_name: &str) -> Result<DynamicDecoder, SocketError> {
+        let set = schema::parse(proto_defs)?;
+        if schema::find_message(&set, type_name).is_none() {
+            return Err(SocketError::new(format!(
+                "Definitions don't contain {}",
+                type_name
+            )));
+        }
+        return Ok(DynamicDecoder {
+            set: set,
+            type_name: type_name.trim_start_matches('.').to_string(),
+        });
+    }
+
+    pub fn type_name(&self) -> &str {
+        return &self.type_name;
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> Result<DynamicMessage, SocketError> {
+        let mut input = Reader {
+            bytes: bytes,
+            type_name: &self.type_name,
+        };
+        return self.decode_message(&self.type_name, &mut input, None, 0);
+    }
+
+    // reads fields until input runs out, or until the end of group_number,
+    // depth messages into the one decoded
+    fn decode_message(
+        &self,
+        type_name: &str,
+        input: &mut Reader,
+        group_number: Option<u64>,
+        depth: usize,
+    ) -> Result<DynamicMessage, SocketError> {
+        let type_name = type_name.trim_start_matches('.');
+        if depth > MAX_DEPTH {
+            return Err(malformed(type_name, "nested too deeply"));
+        }
+        let descriptor = match schema::find_message(&self.set, type_name) {
+            Some(descriptor) => descriptor,
+            None => {
+                return Err(SocketError::new(format!(
+                    "Definitions don't contain {}",
+                    type_name
+                )));
+            }
+        };
+
+        // indexed like descriptor.field
+        let mut values: Vec<Option<Value>> = vec![None; descriptor.field.len()];
+        loop {
+            if input.is_empty() {
+                if group_number.is_some() {
+                    return Err(malformed(type_name, "group never ends"));
+                }
+                break;
+            }
+            let key = input.varint()?;
+            let (number, wire_type) = (key >> 3, key & 7);
+            if wire_type == WIRE_END_GROUP {
+                if group_number != Some(number) {
+                    return Err(malformed(type_name, "unexpected end of group"));
+                }
+                break;
+            }
+
+            let index = match descriptor
+                .field
+                .iter()
+                .position(|field| field.number() as u64 == number)
+            {
+                Some(index) => index,
+                None => {
+                    input.skip(number, wire_type, depth)?;
+                    continue;
+                }
+            };
+            let field = &descriptor.field[index];
+            let slot = &mut values[index];
+
+            if field.label() == Label::Repeated {
+                let mut list = match slot.take() {
+                    Some(Value::List(list)) => list,
+                    _ => Vec::new(),
+                };
+                let packable = wire_type_of(field.r#type()) != WIRE_LEN
+                    && wire_type_of(field.r#type()) != WIRE_START_GROUP;
+                if packable && wire_type == WIRE_LEN {
+                    let mut packed = Reader {
+                        bytes: input.len_delimited()?,
+                        type_name: input.type_name,
+                    };
+                    while !packed.is_empty() {
+                        list.push(self.decode_value(field, number, &mut packed, depth)?);
+                    }
+                } else {
+                    self.check_wire_type(type_name, field, wire_type)?;
+                    list.push(self.decode_value(field, number, input, depth)?);
+                }
+                *slot = Some(Value::List(list));
+            } else {
+                self.check_wire_type(type_name, field, wire_type)?;
+                let value = self.decode_value(field, number, input, depth)?;
+                // the last occurrence of a singular field wins, except that
+                // messages are merged
+                *slot = match (slot.take(), value) {
+                    (Some(Value::Message(mut first)), Value::Message(second)) => {
+                        merge(&mut first, second);
+                        Some(Value::Message(first))
+                    }
+                    (_, value) => Some(value),
+                };
+            }
+        }
+
+        let fields: Vec<Field> = descriptor
+            .field
+            .iter()
+            .zip(values)
+            .filter_map(|(field, value)| {
+                Some(Field {
+                    name: field.name().to_string(),
+                    number: field.number() as u32,
+                    value: value?,
+                })
+            })
+            .collect();
+        return Ok(DynamicMessage {
+            type_name: type_name.to_string(),
+            fields: fields,
+        });
+    }
+
+    fn check_wire_type(
+        &self,
+        type_name: &str,
+        field: &FieldDescriptorProto,
+        wire_type: u64,
+    ) -> Result<(), SocketError> {
+        if wire_type_of(field.r#type()) != wire_type {
+            return Err(malformed(
+                type_name,
+                &format!("field {} has wire type {}", field.name(), wire_type),
+            ));
+        }
+        return Ok(());
+    }
+
+    fn decode_value(
+        &self,
+        field: &FieldDescriptorProto,
+        number: u64,
+        input: &mut Reader,
+        depth: usize,
+    ) -> Result<Value, SocketError> {
+        let value = match field.r#type() {
+            Type::Double => Value::F64(f64::from_bits(input.fixed64()?)),
+            Type::Float => Value::F32(f32::from_bits(input.fixed32()?)),
+            Type::Int64 => Value::I64(input.varint()? as i64),
+            Type::Uint64 => Value::U64(input.varint()?),
+            Type::Int32 => Value::I32(input.varint()? as i32),
+            Type::Fixed64 => Value::U64(input.fixed64()?),
+            Type::Fixed32 => Value::U32(input.fixed32()?),
+            Type::Bool => Value::Bool(input.varint()? != 0),
+            Type::String => match String::from_utf8(input.len_delimited()?.to_vec()) {
+                Ok(value) => Value::String(value),
+                Err(_) => {
+                    return Err(malformed(
+                        input.type_name,
+                        &format!("field {} isn't UTF-8", field.name()),
+                    ));
+                }
+            },
+            Type::Group => Value::Message(self.decode_message(
+                field.type_name(),
+                input,
+                Some(number),
+                depth + 1,
+            )?),
+            Type::Message => {
+                let mut nested = Reader {
+                    bytes: input.len_delimited()?,
+                    type_name: input.type_name,
+                };
+                Value::Message(self.decode_message(
+                    field.type_name(),
+                    &mut nested,
+                    None,
+                    depth + 1,
+                )?)
+            }
+            Type::Bytes => Value::Bytes(input.len_delimited()?.to_vec()),
+            Type::Uint32 => Value::U32(input.varint()? as u32),
+            Type::Enum => {
+                let number = input.varint()? as i32;
+                let name = schema::find_enum(&self.set, field.type_name()).and_then(|found| {
+                    found
+                        .value
+                        .iter()
+                        .find(|value| value.number() == number)
+                        .map(|value| value.name().to_string())
+                });
+                Value::Enum {
+                    number: number,
+                    name: name,
+                }
+            }
+            Type::Sfixed32 => Value::I32(input.fixed32()? as i32),
+            Type::Sfixed64 => Value::I64(input.fixed64()? as i64),
+            Type::Sint32 => {
+                let raw = input.varint()? as u32;
+                Value::I32(((raw >> 1) as i32) ^ -((raw & 1) as i32))
+            }
+            Type::Sint64 => {
+                let raw = input.varint()?;
+                Value::I64(((raw >> 1) as i64) ^ -((raw & 1) as i64))
+            }
+        };
+        return Ok(value);
+    }
+}
+
+// a singular message field seen twice is the two merged, field by field
+fn merge(into: &mut DynamicMessage, from: DynamicMessage) {
+    for field in from.fields {
+        match into
+            .fields
+            .iter_mut()
+            .find(|existing| existing.number == field.number)
+        {
+            Some(existing) => match (&mut existing.value, field.value) {
+                (Value::List(first), Value::List(second)) => first.extend(second),
+                (Value::Message(first), Value::Message(second)) => merge(first, second),
+                (value, other) => *value = other,
+            },
+            None => into.fields.push(field),
+        }
+    }
+}
+
+impl Node {
+    // A decoder for topic's body, from the definitions its publisher announced
+    pub fn dynamic_decoder(&self, topic: &str) -> Result<DynamicDecoder, SocketError> {
+        let state = self.state.lock().unwrap();
+        match state.topic_schema(topic) {
+            Some((type_name, proto_defs)) => {
+                return DynamicDecoder::new(&proto_defs, &type_name);
+            }
+            None => {
+                return Err(SocketError::new(format!(
+                    "No definitions announced for {}",
+                    topic
+                )));
+            }
+        }
+    }
+
+    // Subscribes to topic without knowing its type, bodies are decoded with
+    // the definitions their publisher announced. Samples that arrive before
+    // there is a decoder, or that fail to decode, are dropped.
+    pub fn subscribe_dynamic<F>(&self, topic: &str, cb: F) -> Result<(), SocketError>
+    where
+        F: Fn(DynamicMessage) + Send + 'static,
+    {
+        let name = topic.to_string();
+        let state = Arc::downgrade(&self.state);
+        // by publisher, compatible ones may announce other definitions
+        let decoders: Mutex<HashMap<u64, DynamicDecoder>> = Mutex::new(HashMap::new());
+        let callback = move |sample: &Sample| {
+            let publisher_id = sample.info.publisher_id;
+            let mut decoders = decoders.lock().unwrap();
+            if let Entry::Vacant(entry) = decoders.entry(publisher_id) {
+                let found = match state.upgrade() {
+                    Some(state) => state.lock().unwrap().publisher_schema(&name, publisher_id),
+                    None => None,
+                };
+                if let Some((type_name, proto_defs)) = found {
+                    match DynamicDecoder::new(&proto_defs, &type_name) {
+                        Ok(found) => {
+                            entry.insert(found);
+                        }
+                        Err(err) => println!("No decoder for {}: {}", name, err),
+                    }
+                }
+            }
+            match decoders.get(&publisher_id) {
+                Some(decoder) => match decoder.decode(&sample.body) {
+                    Ok(message) => cb(message),
+                    Err(err) => println!("Dropping sample on {}: {}", name, err),
+                },
+                None => println!("Dropping sample on {}: no definitions", name),
+            }
+        };
+        self.add_subscription(
+            topic,
+            None,
+            &SubscriptionConfig::default(),
+            Some(Box::new(callback)),
+        )?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::{AnnounceConfig, NodeConfig};
+    use prost_types::{
+        DescriptorProto, EnumDescriptorProto, EnumValueDescriptorProto, FileDescriptorProto,
+    };
+    use std::time::{Duration, Instant};
+
+    fn field(name: &str, number: i32, field_type: Type, label: Label) -> FieldDescriptorProto {
+        return FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            r#type: Some(field_type as i32),
+            label: Some(label as i32),
+            ..Default::default()
+        };
+    }
+
+    fn typed_field(
+        name: &str,
+        number: i32,
+        field_type: Type,
+        label: Label,
+        type_name: &str,
+    ) -> FieldDescriptorProto {
+        return FieldDescriptorProto {
+            type_name: Some(type_name.to_string()),
+            ..field(name, number, field_type, label)
+        };
+    }
+
+    fn message(name: &str, fields: Vec<FieldDescriptorProto>) -> DescriptorProto {
+        return DescriptorProto {
+            name: Some(name.to_string()),
+            field: fields,
+            ..Default::default()
+        };
+    }
+
+    fn defs(messages: Vec<DescriptorProto>, enums: Vec<EnumDescriptorProto>) -> Vec<u8> {
+        let set = FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("pkg.proto".to_string()),
+                package: Some("pkg".to_string()),
+                message_type: messages,
+                enum_type: enums,
+                ..Default::default()
+            }],
+        };
+        return prost::Message::encode_to_vec(&set);
+    }
+
+    // Point, a Path of them and a Tree of itself
+    fn shapes() -> Vec<u8> {
+        let point = message(
+            "Point",
+            vec![
+                field("x", 1, Type::Double, Label::Optional),
+                field("y", 2, Type::Double, Label::Optional),
+            ],
+        );
+        let path = message(
+            "Path",
+            vec![
+                field("name", 1, Type::String, Label::Optional),
+                typed_field("points", 2, Type::Message, Label::Repeated, ".pkg.Point"),
+                field("offsets", 3, Type::Sint32, Label::Repeated),
+                typed_field("kind", 4, Type::Enum, Label::Optional, ".pkg.Kind"),
+            ],
+        );
+        let tree = message(
+            "Tree",
+            vec![
+                typed_field("child", 1, Type::Message, Label::Optional, ".pkg.Tree"),
+                field("leaf", 2, Type::Int32, Label::Optional),
+            ],
+        );
+        let kind = EnumDescriptorProto {
+            name: Some("Kind".to_string()),
+            value: vec![
+                EnumValueDescriptorProto {
+                    name: Some("OPEN".to_string()),
+                    number: Some(0),
+                    ..Default::default()
+                },
+                EnumValueDescriptorProto {
+                    name: Some("CLOSED".to_string()),
+                    number: Some(1),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        return defs(vec![point, path, tree], vec![kind]);
+    }
+
+    fn varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn key(out: &mut Vec<u8>, number: u64, wire_type: u64) {
+        varint(out, number << 3 | wire_type);
+    }
+
+    fn len_field(out: &mut Vec<u8>, number: u64, bytes: &[u8]) {
+        key(out, number, WIRE_LEN);
+        varint(out, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+
+    fn double_field(out: &mut Vec<u8>, number: u64, value: f64) {
+        key(out, number, WIRE_FIXED64);
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // levels of Tree around a leaf
+    fn tree(levels: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        key(&mut bytes, 2, WIRE_VARINT);
+        varint(&mut bytes, 7);
+        for _ in 0..levels {
+            let mut outer = Vec::new();
+            len_field(&mut outer, 1, &bytes);
+            bytes = outer;
+        }
+        return bytes;
+    }
+
+    #[test]
+    fn nested_and_repeated_fields() {
+        let mut first = Vec::new();
+        double_field(&mut first, 1, 1.5);
+        double_field(&mut first, 2, -2.0);
+        let mut second = Vec::new();
+        double_field(&mut second, 1, 3.0);
+
+        let mut bytes = Vec::new();
+        len_field(&mut bytes, 1, b"path");
+        len_field(&mut bytes, 2, &first);
+        // packed -1 and 2, then 3 on its own
+        len_field(&mut bytes, 3, &[1, 4]);
+        len_field(&mut bytes, 2, &second);
+        key(&mut bytes, 3, WIRE_VARINT);
+        varint(&mut bytes, 6);
+        key(&mut bytes, 4, WIRE_VARINT);
+        varint(&mut bytes, 1);
+
+        let decoder = DynamicDecoder::new(&shapes(), "pkg.Path").unwrap();
+        let path = decoder.decode(&bytes).unwrap();
+        assert_eq!(path.type_name, "pkg.Path");
+        assert_eq!(path.get("name"), Some(&Value::String("path".to_string())));
+        let points = match path.get("points") {
+            Some(Value::List(points)) => points,
+            other => panic!("points decoded as {:?}", other),
+        };
+        assert_eq!(points.len(), 2);
+        match (&points[0], &points[1]) {
+            (Value::Message(first), Value::Message(second)) => {
+                assert_eq!(first.type_name, "pkg.Point");
+                assert_eq!(first.get("x"), Some(&Value::F64(1.5)));
+                assert_eq!(first.get("y"), Some(&Value::F64(-2.0)));
+                assert_eq!(second.get("x"), Some(&Value::F64(3.0)));
+                assert_eq!(second.get("y"), None);
+            }
+            other => panic!("points decoded as {:?}", other),
+        }
+        assert_eq!(
+            path.get("offsets"),
+            Some(&Value::List(vec![
+                Value::I32(-1),
+                Value::I32(2),
+                Value::I32(3)
+            ]))
+        );
+        assert_eq!(
+            path.get("kind"),
+            Some(&Value::Enum {
+                number: 1,
+                name: Some("CLOSED".to_string())
+            })
+        );
+
+        let decoder = DynamicDecoder::new(&shapes(), "pkg.Tree").unwrap();
+        let tree = decoder.decode(&tree(2)).unwrap();
+        assert_eq!(tree.get("child.child.leaf"), Some(&Value::I32(7)));
+        assert_eq!(tree.get("child.leaf"), None);
+    }
+
+    #[test]
+    fn self_referential_messages_nest_only_so_deep() {
+        let decoder = DynamicDecoder::new(&shapes(), "pkg.Tree").unwrap();
+        assert!(decoder.decode(&tree(MAX_DEPTH)).is_ok());
+        assert!(decoder.decode(&tree(MAX_DEPTH + 1)).is_err());
+        assert!(decoder.decode(&tree(10 * MAX_DEPTH)).is_err());
+
+        // and so do groups nobody defined
+        let mut bytes = Vec::new();
+        for _ in 0..100_000 {
+            key(&mut bytes, 9, WIRE_START_GROUP);
+        }
+        for _ in 0..100_000 {
+            key(&mut bytes, 9, WIRE_END_GROUP);
+        }
+        assert!(decoder.decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn samples_are_decoded_with_their_publishers_definitions() {
+        let node = |name: &str| {
+            return Node::new(&NodeConfig {
+                name: name.to_string(),
+                max_nodes: 16,
+                handle_signals: false,
+            })
+            .unwrap();
+        };
+        let count = field("count", 1, Type::Int32, Label::Optional);
+        let label = field("label", 2, Type::String, Label::Optional);
+        let v1 = defs(vec![message("V1", vec![count.clone()])], vec![]);
+        let v2 = defs(vec![message("V2", vec![count, label])], vec![]);
+
+        let a = node("dynamic_v1");
+        let b = node("dynamic_v2");
+        let c = node("dynamic_sub");
+        a.announce("/dynamic", "", "pkg.V1", &v1).unwrap();
+        let config = AnnounceConfig {
+            compatible: true,
+            ..Default::default()
+        };
+        b.announce_with("/dynamic", "", "pkg.V2", &v2, &config)
+            .unwrap();
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let messages = received.clone();
+        c.subscribe_dynamic("/dynamic", move |message| {
+            messages.lock().unwrap().push(message);
+        })
+        .unwrap();
+
+        let mut body = Vec::new();
+        key(&mut body, 1, WIRE_VARINT);
+        varint(&mut body, 3);
+        len_field(&mut body, 2, b"three");
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            a.publish("/dynamic", b"", &body).unwrap();
+            b.publish("/dynamic", b"", &body).unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+            let messages = received.lock().unwrap();
+            let v1 = messages.iter().find(|m| m.type_name == "pkg.V1");
+            let v2 = messages.iter().find(|m| m.type_name == "pkg.V2");
+            if let (Some(v1), Some(v2)) = (v1, v2) {
+                assert_eq!(v1.get("count"), Some(&Value::I32(3)));
+                assert_eq!(v1.get("label"), None);
+                assert_eq!(v2.get("label"), Some(&Value::String("three".to_string())));
+                return;
+            }
+        }
+        panic!("didn't hear from both publishers");
+    }
+}
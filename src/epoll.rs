@@ -1,25 +1,35 @@
-use crate::errors::SocketError;
+use crate::errors::{ErrorKind, SocketError};
 use crate::event::EventFd;
-use sendfd::RecvWithFd;
+use crate::protocol::{MAX_FDS, MAX_MESSAGE_BYTES};
+use crate::signal::SignalFd;
+use crate::timer::TimerFd;
 use std::collections::HashMap;
-use std::os::fd::{AsRawFd, FromRawFd, RawFd};
-use std::os::unix::net::{UnixListener, UnixStream}; // needed for from_raw_fd
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
 
 enum Described {
     UnixListener(UnixListener),
-    UnixStream(UnixStream),
+    UnixStream((UnixStream, Vec<RawFd>)), // and fds of a packet yet to come
     EventFd((EventFd, u64)),
+    SignalFd(SignalFd),
+    TimerFd((TimerFd, u64)),
 }
 
 pub struct Packet {
-    bytes: Vec<u8>,
-    fds: Vec<RawFd>,
+    pub source: u64, // key of the stream it arrived on
+    pub bytes: Vec<u8>,
+    pub fds: Vec<RawFd>,
 }
 
 pub enum DescribedInput {
     UnixStream(UnixStream), // product of listener
     Packet(Packet),         // produce of unix stream
-    Event(u64),             // product of event
+    Event(u64, u64),        // key and id of an incremented event
+    Signal(u32),            // product of signal
+    Timer(u64, u64),        // key and id of an expired timer
+    Hangup(u64),            // unix stream closed by the other side
+    Pending,                // fds for a packet still to arrive
 }
 
 pub struct Epoll {
@@ -27,27 +37,99 @@ pub struct Epoll {
     described: HashMap<u64, Described>,
 }
 
-fn get_stream_input(stream: &UnixStream) -> Result<DescribedInput, SocketError> {
-    let mut bytes: Vec<u8> = vec![0; 1024];
-    let mut fds: Vec<RawFd> = vec![-1; 3];
+fn close_fds(fds: &[RawFd]) {
+    for fd in fds.iter() {
+        unsafe {
+            libc::close(*fd);
+        }
+    }
+}
 
-    match stream.recv_with_fd(&mut bytes, &mut fds) {
-        Err(err) => {
+// Packets with no bytes only carry fds belonging to the next packet, they're
+// held in pending until it arrives
+fn get_stream_input(
+    key: u64,
+    stream: &UnixStream,
+    pending: &mut Vec<RawFd>,
+) -> Result<DescribedInput, SocketError> {
+    let mut bytes: Vec<u8> = vec![0; MAX_MESSAGE_BYTES];
+    // u64 for the alignment cmsghdr needs
+    let space = unsafe { libc::CMSG_SPACE((MAX_FDS * size_of::<RawFd>()) as u32) } as usize;
+    let mut control: Vec<u64> = vec![0; space.div_ceil(8)];
+    let mut iov = libc::iovec {
+        iov_base: bytes.as_mut_ptr() as *mut libc::c_void,
+        iov_len: bytes.len(),
+    };
+    let mut fds: Vec<RawFd> = Vec::new();
+    let nbytes: usize;
+    let flags: libc::c_int;
+    unsafe {
+        let mut header: libc::msghdr = std::mem::zeroed();
+        header.msg_iov = &mut iov;
+        header.msg_iovlen = 1;
+        header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        header.msg_controllen = space as _;
+        let ret = libc::recvmsg(stream.as_raw_fd(), &mut header, libc::MSG_CMSG_CLOEXEC);
+        if ret == -1 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ECONNRESET) {
+                return Ok(DescribedInput::Hangup(key));
+            }
             return Err(SocketError::new(format!(
                 "Failed to receive from stream: {}",
                 err
             )));
         }
-        Ok((nbytes, nfds)) => {
-            println!("received: {}B, {}fd", nbytes, nfds);
-            bytes.truncate(nbytes);
-            fds.truncate(nfds);
-            return Ok(DescribedInput::Packet(Packet {
-                bytes: bytes,
-                fds: fds,
-            }));
+        nbytes = ret as usize;
+        flags = header.msg_flags;
+
+        let mut cmsg = libc::CMSG_FIRSTHDR(&header);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / size_of::<RawFd>() {
+                    fds.push(std::ptr::read_unaligned(data.add(i)));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&header, cmsg);
+        }
+    }
+
+    if flags & (libc::MSG_TRUNC | libc::MSG_CTRUNC) != 0 {
+        // what arrived is incomplete, including any fds held for it
+        close_fds(&fds);
+        close_fds(pending);
+        pending.clear();
+        return Err(SocketError::with_kind(
+            ErrorKind::Protocol,
+            format!(
+                "Dropped truncated control message from stream {}: {}",
+                key,
+                if flags & libc::MSG_TRUNC != 0 {
+                    "more than the largest message"
+                } else {
+                    "too many fds"
+                }
+            ),
+        ));
+    }
+    if nbytes == 0 {
+        if fds.is_empty() {
+            // end of stream
+            return Ok(DescribedInput::Hangup(key));
         }
+        pending.append(&mut fds);
+        return Ok(DescribedInput::Pending);
     }
+
+    bytes.truncate(nbytes);
+    pending.append(&mut fds);
+    return Ok(DescribedInput::Packet(Packet {
+        source: key,
+        bytes: bytes,
+        fds: std::mem::take(pending),
+    }));
 }
 
 fn new_connection(listener: &UnixListener) -> Result<DescribedInput, SocketError> {
@@ -103,11 +185,13 @@ impl Epoll {
         return Ok(());
     }
 
-    pub fn add_stream(&mut self, stream: UnixStream) -> Result<(), SocketError> {
+    // returns the key packets from this stream will be tagged with
+    pub fn add_stream(&mut self, stream: UnixStream) -> Result<u64, SocketError> {
         let key: u64 = stream.as_raw_fd() as u64;
         self.add_trigger(stream.as_raw_fd())?;
-        self.described.insert(key, Described::UnixStream(stream));
-        return Ok(());
+        self.described
+            .insert(key, Described::UnixStream((stream, Vec::new())));
+        return Ok(key);
     }
 
     pub fn add_event(&mut self, id: u64, event: EventFd) -> Result<(), SocketError> {
@@ -117,6 +201,20 @@ impl Epoll {
         return Ok(());
     }
 
+    pub fn add_timer(&mut self, id: u64, timer: TimerFd) -> Result<(), SocketError> {
+        let key: u64 = timer.as_raw_fd() as u64;
+        self.add_trigger(timer.as_raw_fd())?;
+        self.described.insert(key, Described::TimerFd((timer, id)));
+        return Ok(());
+    }
+
+    pub fn add_signal(&mut self, signal: SignalFd) -> Result<(), SocketError> {
+        let key: u64 = signal.as_raw_fd() as u64;
+        self.add_trigger(signal.as_raw_fd())?;
+        self.described.insert(key, Described::SignalFd(signal));
+        return Ok(());
+    }
+
     pub fn add_listener(&mut self, listener: UnixListener) -> Result<(), SocketError> {
         let key: u64 = listener.as_raw_fd() as u64;
         self.add_trigger(listener.as_raw_fd())?;
@@ -125,30 +223,86 @@ impl Epoll {
         return Ok(());
     }
 
+    // stops watching and closes whatever was added under key
+    pub fn remove(&mut self, key: u64) -> Result<(), SocketError> {
+        match self.described.remove(&key) {
+            None => {
+                return Err(SocketError::new(format!("Missing key: {}", key)));
+            }
+            Some(Described::UnixStream((_, pending))) => {
+                close_fds(&pending);
+            }
+            Some(_) => {}
+        }
+        unsafe {
+            // closing the fd would remove it too, but it may have been dup'd
+            let ret = libc::epoll_ctl(
+                self.raw_fd,
+                libc::EPOLL_CTL_DEL,
+                key as RawFd,
+                std::ptr::null_mut(),
+            );
+            if ret == -1 && std::io::Error::last_os_error().raw_os_error() != Some(libc::EBADF) {
+                return Err(SocketError::new(format!(
+                    "Failed to remove from epoll: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+        }
+        return Ok(());
+    }
+
     pub fn next(&mut self) -> Result<DescribedInput, SocketError> {
+        loop {
+            if let Some(input) = self.wait(None)? {
+                return Ok(input);
+            }
+        }
+    }
+
+    // Like next(), giving up with None after timeout. Timeouts round up to
+    // the next millisecond.
+    pub fn wait(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<DescribedInput>, SocketError> {
+        let timeout_ms: libc::c_int = match timeout {
+            Some(timeout) => timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
         unsafe {
             let mut event = libc::epoll_event { events: 0, u64: 0 };
-            let ret = libc::epoll_wait(self.raw_fd, &mut event, 1, -1);
+            let ret = libc::epoll_wait(self.raw_fd, &mut event, 1, timeout_ms);
             if ret < 0 {
                 return Err(SocketError::new(format!(
                     "Failed to poll: {}",
                     std::io::Error::last_os_error(),
                 )));
             }
+            if ret == 0 {
+                return Ok(None);
+            }
 
             let key: u64 = event.u64;
-            match self.described.get(&key) {
-                Some(Described::UnixStream(stream)) => {
-                    return get_stream_input(stream);
+            match self.described.get_mut(&key) {
+                Some(Described::UnixStream((stream, pending))) => {
+                    return get_stream_input(key, stream, pending).map(Some);
                 }
                 Some(Described::UnixListener(listener)) => {
-                    return new_connection(listener);
+                    return new_connection(listener).map(Some);
                 }
                 Some(Described::EventFd((event, id))) => {
                     // decrement the event and return the id for the user to
                     // match up with
                     event.decr()?;
-                    return Ok(DescribedInput::Event(*id));
+                    return Ok(Some(DescribedInput::Event(key, *id)));
+                }
+                Some(Described::SignalFd(signal)) => {
+                    return Ok(Some(DescribedInput::Signal(signal.read()?)));
+                }
+                Some(Described::TimerFd((timer, id))) => {
+                    timer.read()?;
+                    return Ok(Some(DescribedInput::Timer(key, *id)));
                 }
                 None => {
                     return Err(SocketError::new(format!("Missing key: {}", key)));
@@ -156,7 +310,9 @@ impl Epoll {
             }
         }
     }
+}
 
+impl Drop for Epoll {
     fn drop(&mut self) {
         unsafe {
             libc::close(self.raw_fd);
@@ -1,13 +1,40 @@
 use std::fmt;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    Other,
+    // a subscriber expects a different type than its publisher announced
+    SchemaMismatch,
+    // a subscriber asks for more than its publisher offers, see QosProfile
+    IncompatibleQos,
+    // a reliable publisher gave up waiting on a subscriber to catch up
+    WouldBlock,
+    // a peer sent something that doesn't follow the control protocol
+    Protocol,
+    // the node has shut down, nothing more will arrive
+    Shutdown,
+}
+
 #[derive(Debug)]
 pub struct SocketError {
     message: String,
+    kind: ErrorKind,
 }
 
 impl SocketError {
     pub fn new(descr: String) -> SocketError {
-        return SocketError { message: descr };
+        return SocketError::with_kind(ErrorKind::Other, descr);
+    }
+
+    pub fn with_kind(kind: ErrorKind, descr: String) -> SocketError {
+        return SocketError {
+            message: descr,
+            kind: kind,
+        };
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        return self.kind;
     }
 }
 
@@ -1,13 +1,47 @@
 use crate::errors::SocketError;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, RawFd};
 
 pub struct EventFd {
     raw_fd: libc::c_int,
 }
 
-impl EventFd {
-    pub fn new() -> Result<EventFd, SocketError> {
+pub struct EventFdBuilder {
+    initial: u32,
+    flags: libc::c_int,
+}
+
+impl EventFdBuilder {
+    pub fn initial(mut self, value: u32) -> EventFdBuilder {
+        self.initial = value;
+        return self;
+    }
+
+    // each decr() takes 1 off the counter rather than resetting it to 0
+    pub fn semaphore(self, enable: bool) -> EventFdBuilder {
+        return self.flag(libc::EFD_SEMAPHORE, enable);
+    }
+
+    // decr() fails with EAGAIN rather than blocking, see try_decr()
+    pub fn nonblocking(self, enable: bool) -> EventFdBuilder {
+        return self.flag(libc::EFD_NONBLOCK, enable);
+    }
+
+    pub fn cloexec(self, enable: bool) -> EventFdBuilder {
+        return self.flag(libc::EFD_CLOEXEC, enable);
+    }
+
+    fn flag(mut self, flag: libc::c_int, enable: bool) -> EventFdBuilder {
+        if enable {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+        return self;
+    }
+
+    pub fn build(&self) -> Result<EventFd, SocketError> {
         unsafe {
-            let fd = libc::eventfd(0, 0);
+            let fd = libc::eventfd(self.initial, self.flags);
             if fd == -1 {
                 return Err(SocketError::new(format!(
                     "Failed to construct eventfd: {}",
@@ -17,6 +51,19 @@ impl EventFd {
             return Ok(EventFd { raw_fd: fd });
         }
     }
+}
+
+impl EventFd {
+    pub fn new() -> Result<EventFd, SocketError> {
+        return EventFd::builder().build();
+    }
+
+    pub fn builder() -> EventFdBuilder {
+        return EventFdBuilder {
+            initial: 0,
+            flags: 0,
+        };
+    }
 
     pub fn dup(&self) -> Result<EventFd, SocketError> {
         unsafe {
@@ -32,14 +79,17 @@ impl EventFd {
     }
 
     pub fn incr(&self) -> Result<(), SocketError> {
+        return self.incr_by(1);
+    }
+
+    pub fn incr_by(&self, n: u64) -> Result<(), SocketError> {
         unsafe {
-            let value: u64 = 1;
-            let ptr: *const u64 = &value;
+            let ptr: *const u64 = &n;
             let ret = libc::write(self.raw_fd, ptr as *const libc::c_void, 8);
 
             if ret == -1 {
                 return Err(SocketError::new(format!(
-                    "Failed to decr event: {}",
+                    "Failed to incr event: {}",
                     std::io::Error::last_os_error()
                 )));
             }
@@ -49,22 +99,144 @@ impl EventFd {
     }
 
     pub fn decr(&self) -> Result<u64, SocketError> {
+        match self.try_decr()? {
+            Some(value) => {
+                return Ok(value);
+            }
+            None => {
+                return Err(SocketError::new(
+                    "Failed to decr event: would block".to_string(),
+                ));
+            }
+        }
+    }
+
+    // Like decr() but returns None instead of an error when a non-blocking
+    // eventfd has nothing to take
+    pub fn try_decr(&self) -> Result<Option<u64>, SocketError> {
         unsafe {
             let mut value: u64 = 0;
-            let mut ptr: *mut u64 = &mut value;
+            let ptr: *mut u64 = &mut value;
             let ret = libc::read(self.raw_fd, ptr as *mut libc::c_void, 8);
             if ret == -1 {
+                let err = std::io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::EAGAIN) {
+                    return Ok(None);
+                }
                 return Err(SocketError::new(format!(
                     "Failed to decr event: {}",
-                    std::io::Error::last_os_error()
+                    err
                 )));
             }
 
-            return Ok(value);
+            return Ok(Some(value));
         }
     }
+}
 
-    pub fn as_raw_fd(&self) -> std::os::fd::RawFd {
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.raw_fd);
+        }
+    }
+}
+
+impl AsFd for EventFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe {
+            return BorrowedFd::borrow_raw(self.raw_fd);
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
         return self.raw_fd;
     }
 }
+
+impl IntoRawFd for EventFd {
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.raw_fd;
+        std::mem::forget(self);
+        return fd;
+    }
+}
+
+impl FromRawFd for EventFd {
+    // fd must be an eventfd, e.g. one received from another process
+    unsafe fn from_raw_fd(fd: RawFd) -> EventFd {
+        return EventFd { raw_fd: fd };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn nonblocking() -> EventFdBuilder {
+        return EventFd::builder().nonblocking(true);
+    }
+
+    #[test]
+    fn empty_nonblocking_events_have_nothing_to_take() {
+        let event = nonblocking().build().unwrap();
+        assert_eq!(event.try_decr().unwrap(), None);
+        assert!(event.decr().is_err());
+    }
+
+    #[test]
+    fn increments_add_up() {
+        let event = nonblocking().initial(1).build().unwrap();
+        event.incr().unwrap();
+        event.incr_by(4).unwrap();
+        assert_eq!(event.try_decr().unwrap(), Some(6));
+        assert_eq!(event.try_decr().unwrap(), None);
+    }
+
+    #[test]
+    fn semaphores_take_one_at_a_time() {
+        let event = nonblocking().semaphore(true).initial(2).build().unwrap();
+        assert_eq!(event.try_decr().unwrap(), Some(1));
+        assert_eq!(event.try_decr().unwrap(), Some(1));
+        assert_eq!(event.try_decr().unwrap(), None);
+    }
+
+    #[test]
+    fn blocking_decr_waits_for_incr() {
+        let event = EventFd::new().unwrap();
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                event.incr_by(3).unwrap();
+            });
+            assert_eq!(event.decr().unwrap(), 3);
+        });
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn cloexec_is_set_on_the_fd() {
+        for enable in [false, true] {
+            let event = EventFd::builder().cloexec(enable).build().unwrap();
+            let flags = unsafe { libc::fcntl(event.as_raw_fd(), libc::F_GETFD) };
+            assert_eq!(flags & libc::FD_CLOEXEC != 0, enable);
+        }
+    }
+
+    #[test]
+    fn dups_share_the_counter() {
+        let event = nonblocking().build().unwrap();
+        let dup = event.dup().unwrap();
+        dup.incr_by(2).unwrap();
+        event.incr().unwrap();
+        assert_eq!(event.try_decr().unwrap(), Some(3));
+        assert_eq!(dup.try_decr().unwrap(), None);
+        drop(event);
+        dup.incr().unwrap();
+        assert_eq!(dup.try_decr().unwrap(), Some(1));
+    }
+}
@@ -0,0 +1,420 @@
+use crate::node::Node;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Poll, Wake, Waker};
+use std::time::{Duration, Instant};
+
+// how long spinning threads wait before checking again for what nothing
+// wakes them about, a condition or another executor's group
+const POLL_PERIOD: Duration = Duration::from_millis(10);
+
+pub(crate) type Job = Box<dyn FnOnce() + Send>;
+
+// Decides which callbacks an Executor may run at the same time. A
+// subscription's callbacks never run alongside each other, whatever group
+// it's in.
+#[derive(Clone)]
+pub struct CallbackGroup {
+    reentrant: bool,
+    running: Arc<AtomicBool>,
+}
+
+impl CallbackGroup {
+    // Callbacks of the group run one at a time. Subscriptions that aren't
+    // given a group share one like this per node.
+    pub fn mutually_exclusive() -> CallbackGroup {
+        return CallbackGroup {
+            reentrant: false,
+            running: Default::default(),
+        };
+    }
+
+    // callbacks of the group may run alongside each other, and any other
+    // group's, on a multi-threaded executor
+    pub fn reentrant() -> CallbackGroup {
+        return CallbackGroup {
+            reentrant: true,
+            running: Default::default(),
+        };
+    }
+
+    fn claim(&self) -> bool {
+        if self.reentrant {
+            return true;
+        }
+        return self
+            .running
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok();
+    }
+
+    fn release(&self) {
+        if !self.reentrant {
+            self.running.store(false, Ordering::Release);
+        }
+    }
+}
+
+struct Task {
+    group: CallbackGroup,
+    // queued at most once while waiting
+    key: Option<u64>,
+    job: Job,
+}
+
+// What nodes added to an executor hand their callbacks to
+pub(crate) struct ExecutorQueue {
+    tasks: Mutex<VecDeque<Task>>,
+    ready: Condvar,
+    cancelled: AtomicBool,
+}
+
+impl ExecutorQueue {
+    // Queues job to run in group, unless one with the same key is already
+    // waiting to, which it's then left to
+    pub(crate) fn push(&self, group: &CallbackGroup, key: Option<u64>, job: Job) {
+        let mut tasks = self.tasks.lock().unwrap();
+        if key.is_some() && tasks.iter().any(|task| task.key == key) {
+            return;
+        }
+        tasks.push_back(Task {
+            group: group.clone(),
+            key: key,
+            job: job,
+        });
+        self.ready.notify_one();
+    }
+
+    // Runs the first queued job its group lets run, waiting until deadline for
+    // one. Returns whether one ran.
+    fn run_one(&self, deadline: Instant) -> bool {
+        let mut tasks = self.tasks.lock().unwrap();
+        loop {
+            if let Some(index) = tasks.iter().position(|task| task.group.claim()) {
+                let task = tasks.remove(index).unwrap();
+                drop(tasks);
+                let _claimed = Claimed {
+                    group: &task.group,
+                    queue: self,
+                };
+                (task.job)();
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline || self.cancelled.load(Ordering::Acquire) {
+                return false;
+            }
+            tasks = self
+                .ready
+                .wait_timeout(tasks, (deadline - now).min(POLL_PERIOD))
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+// Releases a group claimed for a job once it's done, even if it panicked
+struct Claimed<'a> {
+    group: &'a CallbackGroup,
+    queue: &'a ExecutorQueue,
+}
+
+impl Drop for Claimed<'_> {
+    fn drop(&mut self) {
+        self.group.release();
+        // what the group held back may run now, and whoever spins until
+        // something gets to check it
+        self.queue.ready.notify_all();
+    }
+}
+
+// wakes whoever spins until a future completes
+struct QueueWaker {
+    queue: Arc<ExecutorQueue>,
+}
+
+impl Wake for QueueWaker {
+    fn wake(self: Arc<Self>) {
+        self.queue.ready.notify_all();
+    }
+}
+
+// Runs the callbacks of the nodes added to it, on the threads that spin it
+// and only while they do, rather than on the threads of the nodes
+pub struct Executor {
+    queue: Arc<ExecutorQueue>,
+    // spinning, the one that called spin() included
+    threads: usize,
+}
+
+impl Executor {
+    // runs every callback on the thread that spins it
+    pub fn single_threaded() -> Executor {
+        return Executor::multi_threaded(1);
+    }
+
+    // Spinning runs callbacks on threads threads at once, as far as their
+    // callback groups allow. spin_once() only ever uses the calling thread.
+    pub fn multi_threaded(threads: usize) -> Executor {
+        return Executor {
+            queue: Arc::new(ExecutorQueue {
+                tasks: Default::default(),
+                ready: Condvar::new(),
+                cancelled: AtomicBool::new(false),
+            }),
+            threads: threads.max(1),
+        };
+    }
+
+    // From now on node's callbacks only run while the executor spins. A node
+    // is added to one executor at a time, the last it was added to.
+    pub fn add_node(&self, node: &Node) {
+        node.set_executor(Arc::downgrade(&self.queue));
+    }
+
+    // node's callbacks go back to running on its own threads
+    pub fn remove_node(&self, node: &Node) {
+        node.unset_executor(&self.queue);
+    }
+
+    // Runs callbacks until cancel()
+    pub fn spin(&self) {
+        self.spin_until(|| false);
+    }
+
+    // Runs at most one callback, waiting up to timeout for one. Returns
+    // whether one ran.
+    pub fn spin_once(&self, timeout: Duration) -> bool {
+        return self.queue.run_one(Instant::now() + timeout);
+    }
+
+    // Runs callbacks until condition holds, checking it on the calling thread
+    // between them and every so often while there are none. Returns whether
+    // it held, false if cancelled first.
+    pub fn spin_until<F: FnMut() -> bool>(&self, mut condition: F) -> bool {
+        let queue = &self.queue;
+        let done = AtomicBool::new(false);
+        let held = std::thread::scope(|scope| {
+            for _ in 1..self.threads {
+                scope.spawn(|| {
+                    while !done.load(Ordering::Acquire) && !queue.cancelled.load(Ordering::Acquire)
+                    {
+                        queue.run_one(Instant::now() + POLL_PERIOD);
+                    }
+                });
+            }
+            let mut held = false;
+            while !queue.cancelled.load(Ordering::Acquire) {
+                if condition() {
+                    held = true;
+                    break;
+                }
+                queue.run_one(Instant::now() + POLL_PERIOD);
+            }
+            done.store(true, Ordering::Release);
+            return held;
+        });
+        // spinning again waits for the next cancel()
+        queue.cancelled.store(false, Ordering::Release);
+        return held;
+    }
+
+    // Runs callbacks until future completes, which is polled on the calling
+    // thread. Returns its output, None if cancelled first.
+    pub fn spin_until_complete<F: Future>(&self, future: F) -> Option<F::Output> {
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(QueueWaker {
+            queue: self.queue.clone(),
+        }));
+        let mut context = std::task::Context::from_waker(&waker);
+        let mut output: Option<F::Output> = None;
+        self.spin_until(|| match future.as_mut().poll(&mut context) {
+            Poll::Ready(value) => {
+                output = Some(value);
+                true
+            }
+            Poll::Pending => false,
+        });
+        return output;
+    }
+
+    // Makes whatever spins the executor return, from any thread or callback
+    pub fn cancel(&self) {
+        self.queue.cancelled.store(true, Ordering::Release);
+        self.queue.ready.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::NodeConfig;
+    use std::sync::atomic::AtomicUsize;
+
+    // runs job as part of group, with at most max of the group's jobs ever
+    // running at once recorded
+    fn counted(running: &Arc<AtomicUsize>, max: &Arc<AtomicUsize>, done: &Arc<AtomicUsize>) -> Job {
+        let (running, max, done) = (running.clone(), max.clone(), done.clone());
+        return Box::new(move || {
+            let now = running.fetch_add(1, Ordering::AcqRel) + 1;
+            max.fetch_max(now, Ordering::AcqRel);
+            // long enough for the others to catch up, if they may
+            let start = Instant::now();
+            while running.load(Ordering::Acquire) < 2 && start.elapsed() < Duration::from_millis(50)
+            {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            running.fetch_sub(1, Ordering::AcqRel);
+            done.fetch_add(1, Ordering::AcqRel);
+        });
+    }
+
+    // the most jobs of group that ran at once, out of 8 on 4 threads
+    fn most_at_once(group: CallbackGroup) -> usize {
+        let executor = Executor::multi_threaded(4);
+        let (running, max, done) = Default::default();
+        for _ in 0..8 {
+            executor
+                .queue
+                .push(&group, None, counted(&running, &max, &done));
+        }
+        executor.spin_until(|| done.load(Ordering::Acquire) == 8);
+        return max.load(Ordering::Acquire);
+    }
+
+    #[test]
+    fn mutually_exclusive_callbacks_run_one_at_a_time() {
+        assert_eq!(most_at_once(CallbackGroup::mutually_exclusive()), 1);
+    }
+
+    #[test]
+    fn reentrant_callbacks_run_at_once() {
+        assert!(most_at_once(CallbackGroup::reentrant()) > 1);
+    }
+
+    #[test]
+    fn a_key_is_queued_once() {
+        let executor = Executor::single_threaded();
+        let group = CallbackGroup::mutually_exclusive();
+        let runs = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let runs = runs.clone();
+            executor.queue.push(
+                &group,
+                Some(1),
+                Box::new(move || {
+                    runs.fetch_add(1, Ordering::AcqRel);
+                }),
+            );
+        }
+        assert!(executor.spin_once(Duration::from_millis(10)));
+        assert!(!executor.spin_once(Duration::from_millis(10)));
+        assert_eq!(runs.load(Ordering::Acquire), 1);
+    }
+
+    #[test]
+    fn spin_once_times_out() {
+        let executor = Executor::single_threaded();
+        let start = Instant::now();
+        assert!(!executor.spin_once(Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn cancelling_from_a_callback_stops_spinning() {
+        let executor = Arc::new(Executor::single_threaded());
+        let cancelling = executor.clone();
+        executor.queue.push(
+            &CallbackGroup::mutually_exclusive(),
+            None,
+            Box::new(move || cancelling.cancel()),
+        );
+        executor.spin();
+        // and the next spin waits for another
+        assert!(!executor.spin_once(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn spin_until_complete_returns_the_output() {
+        let executor = Executor::single_threaded();
+        let done = Arc::new(AtomicBool::new(false));
+        let setter = done.clone();
+        executor.queue.push(
+            &CallbackGroup::mutually_exclusive(),
+            None,
+            Box::new(move || setter.store(true, Ordering::Release)),
+        );
+        let future = std::future::poll_fn(|_| match done.load(Ordering::Acquire) {
+            true => Poll::Ready(7),
+            false => Poll::Pending,
+        });
+        assert_eq!(executor.spin_until_complete(future), Some(7));
+    }
+
+    #[test]
+    fn a_panicking_callback_releases_its_group() {
+        let executor = Executor::single_threaded();
+        let group = CallbackGroup::mutually_exclusive();
+        executor
+            .queue
+            .push(&group, None, Box::new(|| panic!("callback")));
+        let spun = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            executor.spin_once(Duration::from_millis(10));
+        }));
+        assert!(spun.is_err());
+        executor.queue.push(&group, None, Box::new(|| {}));
+        assert!(executor.spin_once(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn removed_nodes_run_their_own_callbacks() {
+        let node = |name: &str| {
+            return Node::new(&NodeConfig {
+                name: name.to_string(),
+                max_nodes: 16,
+                handle_signals: false,
+            })
+            .unwrap();
+        };
+        let a = node("executor_a");
+        let b = node("executor_b");
+        a.announce("/executor", "", "X", b"").unwrap();
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let called = threads.clone();
+        b.subscribe("/executor", move |_: &[u8], _: &[u8]| {
+            called.lock().unwrap().push(std::thread::current().id());
+        })
+        .unwrap();
+
+        // publishes until a callback has run or timeout passes, spinning
+        // executor if given
+        let call = |executor: Option<&Executor>, timeout: Duration| {
+            threads.lock().unwrap().clear();
+            let start = Instant::now();
+            while threads.lock().unwrap().is_empty() && start.elapsed() < timeout {
+                a.publish("/executor", b"", b"").unwrap();
+                match executor {
+                    Some(executor) => {
+                        executor.spin_once(Duration::from_millis(10));
+                    }
+                    None => std::thread::sleep(Duration::from_millis(10)),
+                }
+            }
+            return threads.lock().unwrap().clone();
+        };
+
+        let executor = Executor::single_threaded();
+        executor.add_node(&b);
+        assert!(call(None, Duration::from_millis(200)).is_empty());
+        let ran = call(Some(&executor), Duration::from_secs(5));
+        assert!(!ran.is_empty());
+        assert!(ran.iter().all(|id| *id == std::thread::current().id()));
+
+        executor.remove_node(&b);
+        let ran = call(None, Duration::from_secs(5));
+        assert!(!ran.is_empty());
+        assert!(ran.iter().all(|id| *id != std::thread::current().id()));
+    }
+}
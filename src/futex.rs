@@ -1,10 +1,19 @@
 use crate::errors::SocketError;
-use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 
+// A 4 byte memfd holding a futex word. The owner waits on it, anyone the fd
+// is sent to can map it and wake the owner.
 pub struct Futex {
     raw_fd: RawFd,
+    word: *mut AtomicU32,
 }
 
+// the word is only ever touched atomically
+unsafe impl Send for Futex {}
+unsafe impl Sync for Futex {}
+
 impl Futex {
     pub fn new() -> Result<Futex, SocketError> {
         unsafe {
@@ -25,7 +34,114 @@ impl Futex {
                 )));
             }
 
-            return Ok(Self { raw_fd: fd });
+            return Futex::from_fd(fd);
+        }
+    }
+
+    // Maps a futex memfd received from another node, takes ownership of fd
+    pub fn from_fd(fd: RawFd) -> Result<Futex, SocketError> {
+        unsafe {
+            let ptr = libc::mmap(
+                std::ptr::null_mut(),
+                4,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                libc::close(fd);
+                return Err(SocketError::new(format!(
+                    "Failed to map futex: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+
+            return Ok(Futex {
+                raw_fd: fd,
+                word: ptr as *mut AtomicU32,
+            });
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        unsafe {
+            return (*self.word).load(Ordering::Acquire);
+        }
+    }
+
+    // Blocks until woken, unless the word no longer holds expected
+    pub fn wait(&self, expected: u32) -> Result<(), SocketError> {
+        return self.wait_timeout(expected, None);
+    }
+
+    // Like wait(), but gives up once timeout has passed, None waits for as
+    // long as it takes
+    pub fn wait_timeout(
+        &self,
+        expected: u32,
+        timeout: Option<Duration>,
+    ) -> Result<(), SocketError> {
+        unsafe {
+            let timespec = timeout.map(|timeout| libc::timespec {
+                tv_sec: timeout.as_secs() as libc::time_t,
+                tv_nsec: timeout.subsec_nanos() as libc::c_long,
+            });
+            let relative: *const libc::timespec = match &timespec {
+                Some(timespec) => timespec,
+                None => std::ptr::null(),
+            };
+            // not FUTEX_PRIVATE_FLAG, the word is shared between processes
+            let ret = libc::syscall(
+                libc::SYS_futex,
+                self.word,
+                libc::FUTEX_WAIT,
+                expected,
+                relative,
+            );
+            if ret == -1 {
+                let err = std::io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EAGAIN) | Some(libc::EINTR) | Some(libc::ETIMEDOUT) => {}
+                    _ => {
+                        return Err(SocketError::new(format!(
+                            "Failed to wait on futex: {}",
+                            err
+                        )));
+                    }
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    // Bumps the word and wakes all waiters
+    pub fn wake(&self) -> Result<(), SocketError> {
+        unsafe {
+            (*self.word).fetch_add(1, Ordering::AcqRel);
+            let ret = libc::syscall(libc::SYS_futex, self.word, libc::FUTEX_WAKE, libc::INT_MAX);
+            if ret == -1 {
+                return Err(SocketError::new(format!(
+                    "Failed to wake futex: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+            return Ok(());
+        }
+    }
+}
+
+impl AsRawFd for Futex {
+    fn as_raw_fd(&self) -> RawFd {
+        return self.raw_fd;
+    }
+}
+
+impl Drop for Futex {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.word as *mut libc::c_void, 4);
+            libc::close(self.raw_fd);
         }
     }
 }
@@ -1,8 +1,49 @@
+#[cfg(feature = "async")]
+mod async_io;
+#[cfg(feature = "serde")]
+mod codec;
+mod dynamic;
 mod epoll;
 mod errors;
 mod event;
+mod executor;
 mod futex;
 mod node;
+mod protocol;
+#[cfg(any(feature = "async", feature = "serde"))]
+mod publisher;
+mod qos;
+mod schema;
 mod shared_segment;
+mod signal;
+mod timer;
+#[cfg(feature = "serde")]
+mod typed;
+mod wait_set;
 
-pub use crate::node::{Node, NodeConfig};
+#[cfg(feature = "bincode")]
+pub use crate::codec::Bincode;
+#[cfg(feature = "cbor")]
+pub use crate::codec::Cbor;
+#[cfg(feature = "serde")]
+pub use crate::codec::Codec;
+#[cfg(any(feature = "bincode", feature = "cbor", feature = "json"))]
+pub use crate::codec::DefaultCodec;
+#[cfg(feature = "json")]
+pub use crate::codec::Json;
+pub use crate::dynamic::{DynamicDecoder, DynamicMessage, Field, Value};
+pub use crate::errors::{ErrorKind, SocketError};
+pub use crate::event::{EventFd, EventFdBuilder};
+pub use crate::executor::{CallbackGroup, Executor};
+pub use crate::node::{
+    topic_id, AnnounceConfig, Context, ContextConfig, DeadlineCallback, LivelinessCallback,
+    LossCallback, Node, NodeConfig, PublisherInfo, Subscription, SubscriptionConfig, TopicInfo,
+    Wakeup,
+};
+#[cfg(any(feature = "async", feature = "serde"))]
+pub use crate::publisher::Publisher;
+pub use crate::qos::{Durability, History, Liveliness, QosProfile, Reliability};
+pub use crate::schema::Schema;
+pub use crate::shared_segment::{MemoryOptions, Sample, SampleInfo, SubscriptionStats};
+pub use crate::timer::TimerFd;
+pub use crate::wait_set::WaitSet;
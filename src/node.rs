@@ -1,256 +1,3498 @@
 use crate::epoll::{DescribedInput, Epoll, Packet};
-use crate::errors::SocketError;
+use crate::errors::{ErrorKind, SocketError};
 use crate::event::EventFd;
+use crate::executor::{CallbackGroup, ExecutorQueue, Job};
 use crate::futex::Futex;
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
+use crate::protocol::{Connection, ControlMessage, Route, EVERY_NODE};
+use crate::qos::{History, Liveliness, QosProfile, Reliability, INITIAL_SAMPLE_BYTES};
+use crate::schema::{self, Schema, SchemaRegistry};
+use crate::shared_segment::{
+    clock_ns, read_sealed, slotsize, write_sealed, MemoryOptions, Sample, SampleInfo,
+    SharedSegmentReader, SharedSegmentWriter, SubscriptionStats, CRC64,
+};
+use crate::signal::SignalFd;
+use crate::timer::TimerFd;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 
 use libc::socket;
-use std::io::prelude::*;
-use std::os::fd::AsFd;
 use std::os::fd::{AsRawFd, FromRawFd, RawFd}; // needed for from_raw_fd
 use std::os::unix::net::{UnixListener, UnixStream};
 
 pub struct NodeConfig {
     pub name: String,
     pub max_nodes: u32,
+    // Block SIGINT/SIGTERM and shut the node down when one arrives. Signals
+    // are only blocked in the thread constructing the node (and threads it
+    // spawns later), so construct the node before starting other threads.
+    pub handle_signals: bool,
 }
 
-pub struct Node {
+// Like NodeConfig, for a Context whose nodes are named as they're made
+pub struct ContextConfig {
+    // processes in the domain, each takes one socket name
+    pub max_nodes: u32,
+    // see NodeConfig::handle_signals, shuts every node of the context down
+    pub handle_signals: bool,
+}
+
+// How a publisher tells a subscriber that there is something new to read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wakeup {
+    // Publishers bump a futex word shared by all of the node's futex
+    // subscriptions, the node's futex thread then checks all of them
+    Futex,
+    // Publishers increment an eventfd belonging to just this subscription,
+    // which is waited on in the socket thread epoll of the node's context
+    EventFd,
+}
+
+#[derive(Clone)]
+pub struct SubscriptionConfig {
+    pub wakeup: Wakeup,
+    // Protobuf type the body is expected to be. Publishers whose announced
+    // definitions can't be decoded as it are refused with SchemaMismatch.
+    pub schema: Option<Schema>,
+    // Accept a schema publishers announce under a different type name, as
+    // long as its definitions are compatible with ours
+    pub compatible: bool,
+    // Called with the number of samples lost whenever some are, before the
+    // sample that follows them is delivered
+    pub on_loss: Option<LossCallback>,
+    // Called with the total number of deadlines missed so far whenever
+    // qos.deadline passes without a sample, counting from the first match
+    pub on_deadline_missed: Option<DeadlineCallback>,
+    // Called when a publisher stops asserting liveliness within
+    // qos.lease_duration, or starts again
+    pub on_liveliness_changed: Option<LivelinessCallback>,
+    // what's asked of publishers, the ones that offer less aren't matched
+    pub qos: QosProfile,
+    // how the segments of publishers are mapped
+    pub memory: MemoryOptions,
+    // Deliver samples with empty bodies, which are never read out of the
+    // segment or memfd they're in. For monitoring tools after only heads.
+    pub heads_only: bool,
+    // Samples from several publishers are delivered in the order each
+    // published them, one publisher's after another's. This merges them by
+    // publish time instead, as far as what's been published so far goes, at
+    // the cost of holding back one sample per publisher.
+    pub time_ordered: bool,
+    // Which of the node's callbacks an Executor may run alongside this
+    // subscription's. None shares the node's mutually exclusive group.
+    pub callback_group: Option<CallbackGroup>,
+}
+
+impl Default for SubscriptionConfig {
+    fn default() -> SubscriptionConfig {
+        return SubscriptionConfig {
+            wakeup: Wakeup::Futex,
+            schema: None,
+            compatible: false,
+            on_loss: None,
+            on_deadline_missed: None,
+            on_liveliness_changed: None,
+            qos: QosProfile::default(),
+            memory: MemoryOptions::default(),
+            heads_only: false,
+            time_ordered: false,
+            callback_group: None,
+        };
+    }
+}
+
+#[derive(Default)]
+pub struct AnnounceConfig {
+    // Publish even if the topic has already been announced with a different
+    // type. If both sides carry definitions they still have to be compatible.
+    pub compatible: bool,
+    // sizes the topic's segment and decides whether publish waits on
+    // subscribers
+    pub qos: QosProfile,
+    // how the topic's segment is set up
+    pub memory: MemoryOptions,
+}
+
+// A publisher of a topic, as seen from this node
+#[derive(Clone, Debug)]
+pub struct PublisherInfo {
+    pub node: String,
+    pub pid: u32,
+    pub head_type_name: String,
+    pub body_type_name: String,
+    pub compatible: bool,
+    pub qos: QosProfile,
+    // differs from the topic's type without having been announced compatible,
+    // so nobody subscribes to it
+    pub conflict: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct TopicInfo {
+    pub topic: String,
+    // fixed by the earliest announcement still around
+    pub head_type_name: String,
+    pub body_type_name: String,
+    pub publishers: Vec<PublisherInfo>,
+}
+
+type Callback = Box<dyn Fn(&Sample) + Send>;
+
+// given how many samples were lost
+pub type LossCallback = Arc<dyn Fn(u64) + Send + Sync>;
+
+// given how many deadlines have been missed in total
+pub type DeadlineCallback = Arc<dyn Fn(u64) + Send + Sync>;
+
+// given the publisher's id (see Node::id()) and whether it's now alive
+pub type LivelinessCallback = Arc<dyn Fn(u64, bool) + Send + Sync>;
+
+enum Doorbell {
+    Futex(Arc<Futex>),
+    EventFd(EventFd),
+}
+
+impl Doorbell {
+    fn ring(&self) -> Result<(), SocketError> {
+        match self {
+            Doorbell::Futex(futex) => {
+                return futex.wake();
+            }
+            Doorbell::EventFd(event) => {
+                return event.incr();
+            }
+        }
+    }
+}
+
+// a peer subscribed to one of our topics
+struct Subscriber {
+    peer: u64,
+    // their subscription's id, for Oversized
+    id: u64,
+    doorbell: Doorbell,
+    // the segment cursor we wait on before overwriting, for reliable ones
+    cursor: Option<usize>,
+}
+
+// a subscription of any node in our process, handed samples directly rather
+// than through the segment
+struct LocalSubscriber {
+    queue: Arc<Mutex<LocalQueue>>,
+    doorbell: Doorbell,
+    // nothing is dropped from its queue, publish waits for room instead
+    reliable: bool,
+}
+
+// Samples handed to a subscription by publications in our process. Locked on
+// its own, never while locking anything else.
+#[derive(Default)]
+struct LocalQueue {
+    samples: VecDeque<Arc<Sample>>,
+    // the shorter of the publisher's and subscriber's, set when matched
+    lifespan: Option<Duration>,
+    stats: SubscriptionStats,
+    // monotonic ns of the last sample handed over, 0 before the first
+    written_ns: u64,
+    // the subscription is gone, publications stop handing it samples
+    closed: bool,
+    // of the reliable publications handing it samples, which wait on these
+    // for it to drain
+    rooms: Vec<Weak<Futex>>,
+}
+
+impl LocalQueue {
+    // lets publications waiting for it to drain know it has, or is gone
+    fn wake_rooms(&mut self) {
+        self.rooms.retain(|room| match room.upgrade() {
+            Some(room) => {
+                if let Err(err) = room.wake() {
+                    println!("Failed to wake publisher: {}", err);
+                }
+                true
+            }
+            None => false,
+        });
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        self.wake_rooms();
+    }
+}
+
+struct Publication {
+    writer: SharedSegmentWriter,
+    announcement: Announcement,
+    subscribers: Vec<Subscriber>,
+    local: Vec<LocalSubscriber>,
+    // what the segment keeps for late subscribers, for late local ones
+    latched: VecDeque<Arc<Sample>>,
+    // woken by reliable subscribers as they read, which publish() waits on
+    // for them to make room. Peers get it along with the segment.
+    room: Arc<Futex>,
+}
+
+// a publisher's segment we read from
+struct Source {
+    peer: u64,
+    reader: SharedSegmentReader,
+    // publisher has left, unmap once everything has been read
+    gone: bool,
+    // asserted liveliness within the lease as of the last check
+    alive: bool,
+    // the segment the publisher grew into, read once reader is drained
+    replacement: Option<SharedSegmentReader>,
+    // read but held back for a time ordered merge with other sources
+    pending: Option<Arc<Sample>>,
+    // see ControlMessage::Segment
+    oversized_from: u64,
+}
+
+// The part of a subscription that is touched while delivering. Kept apart
+// from NodeState so callbacks can call back into the node.
+pub(crate) struct Inbox {
+    sources: Vec<Source>,
+    // None when read through a Subscription rather than delivered
+    callback: Option<Callback>,
+    on_loss: Option<LossCallback>,
+    deadline: Option<Duration>,
+    on_deadline_missed: Option<DeadlineCallback>,
+    lease_duration: Option<Duration>,
+    on_liveliness_changed: Option<LivelinessCallback>,
+    // monotonic ns of the first match or the last missed deadline, whichever
+    // is later, 0 before the first match
+    deadline_from: u64,
+    deadlines_missed: u64,
+    // samples that came outside a segment and the peer they came from, not
+    // yet taken
+    oversized: VecDeque<(u64, Sample)>,
+    oversized_received: u64,
+    // stats of sources that have been unmapped
+    retired: SubscriptionStats,
+    // see SubscriptionConfig::time_ordered
+    time_ordered: bool,
+    heads_only: bool,
+    // what publications in our process handed over, shared with the
+    // subscription's state
+    local: Arc<Mutex<LocalQueue>>,
+    // drops from local already reported to on_loss
+    local_dropped: u64,
+    // like Source::pending, for local
+    local_pending: Option<Arc<Sample>>,
+}
+
+impl Inbox {
+    // the next sample from any source, reporting whatever was lost on the way
+    fn next(&mut self) -> Option<Arc<Sample>> {
+        if !self.time_ordered {
+            if let Some(sample) = self.next_local() {
+                return Some(sample);
+            }
+        } else if self.local_pending.is_none() {
+            self.local_pending = self.next_local();
+        }
+
+        let mut index = 0;
+        while index < self.sources.len() {
+            if !self.time_ordered {
+                if let Some(sample) = self.next_from(index) {
+                    return Some(sample);
+                }
+            } else if self.sources[index].pending.is_none() {
+                self.sources[index].pending = self.next_from(index);
+            }
+
+            // everything it published has been read, so it can be unmapped
+            // without waiting on the others
+            let source = &self.sources[index];
+            if source.gone && source.pending.is_none() {
+                self.retire(index);
+                continue;
+            }
+            index += 1;
+        }
+
+        // the earliest published of what each source has next
+        let local_ns = self
+            .local_pending
+            .as_ref()
+            .map(|sample| sample.info.monotonic_ns);
+        if let Some(source) = self
+            .sources
+            .iter_mut()
+            .filter(|source| source.pending.is_some())
+            .min_by_key(|source| source.pending.as_ref().unwrap().info.monotonic_ns)
+        {
+            let ns = source.pending.as_ref().unwrap().info.monotonic_ns;
+            if local_ns.is_none_or(|local_ns| ns < local_ns) {
+                return source.pending.take();
+            }
+        }
+        if self.local_pending.is_some() {
+            return self.local_pending.take();
+        }
+
+        // oversized samples whose segment is gone
+        let sources = &self.sources;
+        if let Some(index) = self
+            .oversized
+            .iter()
+            .position(|(peer, _)| !sources.iter().any(|source| source.peer == *peer))
+        {
+            self.oversized_received += 1;
+            return self
+                .oversized
+                .remove(index)
+                .map(|(_, sample)| Arc::new(sample));
+        }
+        return None;
+    }
+
+    // the next sample handed over in our process, in the order published
+    fn next_local(&mut self) -> Option<Arc<Sample>> {
+        let (lost, sample) = {
+            let mut local = self.local.lock().unwrap();
+            let now = clock_ns(libc::CLOCK_MONOTONIC);
+            let queued = local.samples.len();
+            let mut sample = local.samples.pop_front();
+            while let (Some(expired), Some(lifespan)) = (&sample, local.lifespan) {
+                if now < expired.info.monotonic_ns + lifespan.as_nanos() as u64 {
+                    break;
+                }
+                local.stats.expired += 1;
+                sample = local.samples.pop_front();
+            }
+            if sample.is_some() {
+                local.stats.received += 1;
+            }
+            if local.samples.len() < queued {
+                local.wake_rooms();
+            }
+            let lost = local.stats.dropped - self.local_dropped;
+            self.local_dropped = local.stats.dropped;
+            (lost, sample)
+        };
+
+        // not holding the queue, on_loss may publish to the topic
+        if lost > 0 {
+            if let Some(on_loss) = &self.on_loss {
+                on_loss(lost);
+            }
+        }
+        match sample {
+            Some(sample) if self.heads_only && !sample.body.is_empty() => {
+                return Some(Arc::new(Sample {
+                    head: sample.head.clone(),
+                    body: Vec::new(),
+                    info: sample.info,
+                }));
+            }
+            sample => {
+                return sample;
+            }
+        }
+    }
+
+    // the next sample from the source at index, in the order published
+    fn next_from(&mut self, index: usize) -> Option<Arc<Sample>> {
+        let source = &mut self.sources[index];
+        loop {
+            // oversized samples go out in sequence with the segment's, in the
+            // place the reader stopped at for them or right away if it's been
+            // lapped past it
+            let next_seq = source.reader.next_sequence();
+            if let Some(position) = self.oversized.iter().position(|(peer, sample)| {
+                return *peer == source.peer && sample.info.sequence <= next_seq;
+            }) {
+                if self.oversized[position].1.info.sequence == next_seq {
+                    source.reader.pass_skipped();
+                }
+                self.oversized_received += 1;
+                return self
+                    .oversized
+                    .remove(position)
+                    .map(|(_, sample)| Arc::new(sample));
+            }
+
+            let lost = source.reader.stats().lost();
+            let sample = source.reader.read();
+            let lost = source.reader.stats().lost() - lost;
+            if lost > 0 {
+                if let Some(on_loss) = &self.on_loss {
+                    on_loss(lost);
+                }
+            }
+            if sample.is_some() {
+                return sample.map(Arc::new);
+            }
+            if source.reader.is_skipped() {
+                // wait for it, unless it was skipped before we subscribed or
+                // the publisher has left without sending it
+                if source.reader.next_sequence() >= source.oversized_from && !source.gone {
+                    return None;
+                }
+                source.reader.pass_skipped();
+                continue;
+            }
+            if source.reader.next_sequence() == next_seq {
+                // nothing more is written to a segment that's been replaced,
+                // so it's drained
+                match source.replacement.take() {
+                    Some(mut replacement) => {
+                        replacement.resume(&source.reader);
+                        source.reader = replacement;
+                    }
+                    None => {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+
+    // unmaps the segment of a publisher that's gone
+    fn retire(&mut self, index: usize) {
+        let source = self.sources.remove(index);
+        self.retired.add(&source.reader.stats());
+        if let (true, Some(on_liveliness_changed)) = (source.alive, &self.on_liveliness_changed) {
+            on_liveliness_changed(source.reader.publisher_id(), false);
+        }
+    }
+
+    // Reports missed deadlines and publishers whose liveliness changed as of
+    // now, returns how long until it has to be checked again
+    fn check_timers(&mut self, now: u64) -> Option<Duration> {
+        let mut next: Option<u64> = None;
+
+        if let Some(deadline) = self.deadline {
+            let deadline = deadline.as_nanos() as u64;
+            let latest = self
+                .sources
+                .iter()
+                .map(|source| source.reader.last_written_ns())
+                .fold(self.deadline_from, u64::max)
+                .max(self.local.lock().unwrap().written_ns);
+            let mut due = latest + deadline;
+            if now >= due {
+                self.deadlines_missed += 1;
+                self.deadline_from = now;
+                due = now + deadline;
+                if let Some(on_deadline_missed) = &self.on_deadline_missed {
+                    on_deadline_missed(self.deadlines_missed);
+                }
+            }
+            next = Some(due - now);
+        }
+
+        if let Some(lease) = self.lease_duration {
+            let lease = lease.as_nanos() as u64;
+            // dead publishers are checked a lease later in case they're back
+            let mut wait = lease;
+            for source in self.sources.iter_mut() {
+                let expires = source.reader.last_asserted_ns() + lease;
+                let alive = now < expires;
+                if alive != source.alive {
+                    source.alive = alive;
+                    if let Some(on_liveliness_changed) = &self.on_liveliness_changed {
+                        on_liveliness_changed(source.reader.publisher_id(), alive);
+                    }
+                }
+                if alive {
+                    wait = wait.min(expires - now);
+                }
+            }
+            next = Some(next.map_or(wait, |next| next.min(wait)));
+        }
+
+        // a zero wait would disarm the timer
+        return next.map(|next| Duration::from_nanos(next.max(1)));
+    }
+
+    fn stats(&self) -> SubscriptionStats {
+        let mut stats = self.retired;
+        stats.received += self.oversized_received;
+        for source in self.sources.iter() {
+            stats.add(&source.reader.stats());
+        }
+        stats.add(&self.local.lock().unwrap().stats);
+        return stats;
+    }
+}
+
+// what a peer told us about a topic it publishes, or what we told them
+#[derive(Clone)]
+struct Announcement {
+    topic: String,
+    head_type_name: String,
+    body_type_name: String,
+    proto_defs: Vec<u8>,
+    node: String,
+    pid: u32,
+    // the announcing node's id, see Node::id()
+    id: u64,
+    announced_at: u64,
+    compatible: bool,
+    qos: QosProfile,
+}
+
+impl Announcement {
+    fn message(&self) -> ControlMessage {
+        return ControlMessage::Announce {
+            topic: self.topic.clone(),
+            head_type_name: self.head_type_name.clone(),
+            body_type_name: self.body_type_name.clone(),
+            proto_defs: self.proto_defs.clone(),
+            node: self.node.clone(),
+            pid: self.pid,
+            id: self.id,
+            announced_at: self.announced_at,
+            compatible: self.compatible,
+            qos: Box::new(self.qos),
+        };
+    }
+
+    fn same_type(&self, other: &Announcement) -> bool {
+        return self.head_type_name == other.head_type_name
+            && self.body_type_name == other.body_type_name;
+    }
+
+    // ties are broken by who announced, so every node agrees on the order
+    fn order(&self) -> (u64, &str, u32) {
+        return (self.announced_at, &self.node, self.pid);
+    }
+}
+
+struct SubscriptionState {
+    topic: String,
+    // type names publishers have to announce, None accepts anything
+    type_names: Option<(String, String)>,
+    // body type name and the definitions the subscriber was built against
+    schema: Option<(String, prost_types::FileDescriptorSet)>,
+    // see SubscriptionConfig::compatible
+    compatible: bool,
+    qos: QosProfile,
+    memory: MemoryOptions,
+    heads_only: bool,
+    wakeup: Wakeup,
+    // the eventfd sent to publishers, for Wakeup::EventFd
+    event: Option<EventFd>,
+    // in the socket thread epoll yet, or never for a Subscription whose
+    // owner waits on the eventfd itself
+    registered: bool,
+    // peers we've sent Subscribe to
+    requested: Vec<u64>,
+    inbox: Arc<Mutex<Inbox>>,
+    // checks deadline and liveliness from the socket thread epoll, which has
+    // a dup of it, armed on the first match
+    timer: Option<TimerFd>,
+    // shared with the inbox
+    local: Arc<Mutex<LocalQueue>>,
+    // see SubscriptionConfig::callback_group
+    group: Option<CallbackGroup>,
+}
+
+// a node of another process, see peer_id()
+struct Peer {
+    // to its process, shared by every node of both
+    stream: Arc<Connection>,
+    route: Route,
+}
+
+impl Peer {
+    fn send(&self, message: &ControlMessage, fds: &[RawFd]) -> Result<(), SocketError> {
+        return message.send(&self.stream, self.route, fds);
+    }
+}
+
+// Everything shared between the node and its threads
+pub(crate) struct NodeState {
+    futex: Arc<Futex>,
+    peers: HashMap<u64, Peer>,
+    publications: HashMap<String, Publication>,
+    // topics each peer has announced, other nodes of our process included
+    announced: HashMap<u64, Vec<Announcement>>,
+    subscriptions: HashMap<u64, SubscriptionState>,
+    schemas: SchemaRegistry,
+    // sent along with our announcements
+    name: String,
+    pid: u32,
+    // stamped on our samples as SampleInfo::publisher_id
+    id: u64,
+    // tells us apart from the other nodes of our context, see Route
+    slot: u32,
+    // runs our callbacks once we've been added to it, see Executor
+    executor: Weak<ExecutorQueue>,
+    // for subscriptions without a group of their own
+    default_group: CallbackGroup,
+    // incremented once we've stopped or our context has, never read
+    stopped: EventFd,
+}
+
+// What the nodes of a context share with its socket thread. Locked after a
+// node's state when both are, never before.
+pub(crate) struct ContextState {
+    // connections to other processes by epoll key, see peer_id()
+    streams: HashMap<u64, Arc<Connection>>,
+    nodes: HashMap<u32, Arc<Mutex<NodeState>>>,
+    next_slot: u32,
+    // unique across nodes, the socket thread tells subscriptions apart by id
+    next_subscription_id: u64,
+    // asserts liveliness of automatic publications, every third of the
+    // shortest lease
+    liveliness_timer: TimerFd,
+    liveliness_period: Option<Duration>,
+    // subscriptions matched within the process that the socket thread has
+    // yet to register, and what it's told about them with
+    matched_locally: Vec<u64>,
+    local_matches: EventFd,
+}
+
+// The socket name, connections and socket thread shared by the nodes of a
+// process, along with anything they publish to one another. Nodes made with
+// Node::new() have one to themselves.
+#[derive(Clone)]
+pub struct Context {
+    shared: Arc<ContextShared>,
+}
+
+struct ContextShared {
+    state: Arc<Mutex<ContextState>>,
     socket_shutdown: EventFd,
-    socket_rx: Receiver<Packet>,
-    socket_thread_handle: std::thread::JoinHandle<Result<(), SocketError>>,
-    futex: Futex,
+    // taken by whoever joins it first, the rest wait for them
+    socket_thread_handle: Mutex<Option<std::thread::JoinHandle<Result<(), SocketError>>>>,
+}
+
+pub struct Node {
+    pub(crate) state: Arc<Mutex<NodeState>>,
+    context: Context,
+    // made by Node::new() for this node, shut down along with it
+    owns_context: bool,
+    futex: Arc<Futex>,
+    futex_stop: Arc<AtomicBool>,
+    futex_thread_handle: Option<std::thread::JoinHandle<Result<(), SocketError>>>,
+}
+
+impl SubscriptionState {
+    fn accepts(&self, announcement: &Announcement) -> Result<(), SocketError> {
+        self.offered(announcement)?;
+        return self.accepts_type(announcement);
+    }
+
+    // whether announcement's publisher offers the qos we ask for, publishers
+    // that don't are skipped whether they're known when subscribing or not
+    fn offered(&self, announcement: &Announcement) -> Result<(), SocketError> {
+        if let Err(err) = self.qos.check_offered(&announcement.qos) {
+            return Err(SocketError::with_kind(
+                err.kind(),
+                format!("{} can't be matched: {}", self.topic, err),
+            ));
+        }
+        return Ok(());
+    }
+
+    // offered(), reporting the publisher when it isn't
+    fn reports_offered(&self, announcement: &Announcement) -> bool {
+        if let Err(err) = self.offered(announcement) {
+            println!("Not subscribing: {}", err);
+            return false;
+        }
+        return true;
+    }
+
+    fn accepts_type(&self, announcement: &Announcement) -> Result<(), SocketError> {
+        if let Some((head_type_name, body_type_name)) = &self.type_names {
+            if *head_type_name != announcement.head_type_name
+                || *body_type_name != announcement.body_type_name
+            {
+                return Err(SocketError::with_kind(
+                    ErrorKind::SchemaMismatch,
+                    format!(
+                        "{} is published as {}/{}, expected {}/{}",
+                        self.topic,
+                        announcement.head_type_name,
+                        announcement.body_type_name,
+                        head_type_name,
+                        body_type_name
+                    ),
+                ));
+            }
+        }
+
+        if let Some((type_name, expected)) = &self.schema {
+            if !self.compatible && *type_name != announcement.body_type_name {
+                return Err(SocketError::with_kind(
+                    ErrorKind::SchemaMismatch,
+                    format!(
+                        "{} is published as {}, expected {}",
+                        self.topic, announcement.body_type_name, type_name
+                    ),
+                ));
+            }
+            if announcement.proto_defs.is_empty() {
+                return Err(SocketError::with_kind(
+                    ErrorKind::SchemaMismatch,
+                    format!(
+                        "{} is published as {} without definitions",
+                        self.topic, announcement.body_type_name
+                    ),
+                ));
+            }
+            let actual = schema::parse(&announcement.proto_defs)?;
+            schema::check_compatible(
+                expected,
+                type_name,
+                &actual,
+                &announcement.body_type_name,
+            )?;
+        }
+        return Ok(());
+    }
+}
+
+// A subscription whose samples are taken by its owner rather than passed to
+// a callback. Always woken through an eventfd, so it can be waited on.
+pub struct Subscription {
+    id: u64,
+    event: EventFd,
+    inbox: Arc<Mutex<Inbox>>,
+    state: Weak<Mutex<NodeState>>,
+    // registration of event with the tokio reactor, made on first use
+    #[cfg(feature = "async")]
+    pub(crate) ready: Option<tokio::io::unix::AsyncFd<EventFd>>,
+    // and of the node's stopped event, see node_stopped()
+    #[cfg(feature = "async")]
+    pub(crate) stopped: Option<tokio::io::unix::AsyncFd<EventFd>>,
+}
+
+impl Subscription {
+    // Returns the next unread sample from any publisher, if there is one
+    pub fn take(&self) -> Option<Sample> {
+        // only copied when our own publication handed it to others too
+        return self
+            .inbox
+            .lock()
+            .unwrap()
+            .next()
+            .map(|sample| Arc::try_unwrap(sample).unwrap_or_else(|shared| (*shared).clone()));
+    }
+
+    // Counts over every publisher this subscription has read from
+    pub fn stats(&self) -> SubscriptionStats {
+        return self.inbox.lock().unwrap().stats();
+    }
+
+    // A dup of the node's stopped event, which is readable once it has shut
+    // down. None if it's gone already.
+    #[cfg(feature = "async")]
+    pub(crate) fn node_stopped(&self) -> Result<Option<EventFd>, SocketError> {
+        match self.state.upgrade() {
+            Some(state) => {
+                return Ok(Some(state.lock().unwrap().stopped.dup()?));
+            }
+            None => {
+                return Ok(None);
+            }
+        }
+    }
+
+    // incremented by publishers, take() everything once it's readable
+    pub(crate) fn event(&self) -> &EventFd {
+        return &self.event;
+    }
+}
+
+impl AsRawFd for Subscription {
+    fn as_raw_fd(&self) -> RawFd {
+        return self.event().as_raw_fd();
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.upgrade() {
+            state.lock().unwrap().subscriptions.remove(&self.id);
+        }
+    }
+}
+
+impl Drop for SubscriptionState {
+    fn drop(&mut self) {
+        self.local.lock().unwrap().close();
+    }
+}
+
+fn deliver(inbox: &Mutex<Inbox>) {
+    let mut inbox = inbox.lock().unwrap();
+    if inbox.callback.is_none() {
+        return;
+    }
+    while let Some(sample) = inbox.next() {
+        if let Some(callback) = &inbox.callback {
+            callback(&sample);
+        }
+    }
+}
+
+// Where subscription id's callbacks run, if not wherever they're due: the
+// executor its node has been added to, in its group
+fn executor_of(state: &NodeState, id: u64) -> Option<(Arc<ExecutorQueue>, CallbackGroup)> {
+    let executor = state.executor.upgrade()?;
+    let sub = state.subscriptions.get(&id)?;
+    let group = sub.group.as_ref().unwrap_or(&state.default_group);
+    return Some((executor, group.clone()));
+}
+
+// Calls the deadline and liveliness callbacks of subscription id that are
+// due, and rearms its timer for the next check
+fn check_timers(state: &Mutex<NodeState>, id: u64) -> Result<(), SocketError> {
+    let inbox = match state.lock().unwrap().subscriptions.get(&id) {
+        Some(sub) => sub.inbox.clone(),
+        None => {
+            return Ok(());
+        }
+    };
+    let next = inbox
+        .lock()
+        .unwrap()
+        .check_timers(clock_ns(libc::CLOCK_MONOTONIC));
+    if let Some(next) = next {
+        let state = state.lock().unwrap();
+        if let Some(timer) = state
+            .subscriptions
+            .get(&id)
+            .and_then(|sub| sub.timer.as_ref())
+        {
+            timer.set(next, Duration::ZERO)?;
+        }
+    }
+    return Ok(());
+}
+
+// Runs job right away, or queues it on the executor as it's keyed there
+fn dispatch(executor: Option<(Arc<ExecutorQueue>, CallbackGroup)>, key: Option<u64>, job: Job) {
+    match executor {
+        Some((executor, group)) => executor.push(&group, key, job),
+        None => job(),
+    }
+}
+
+fn futex_loop(
+    futex: Arc<Futex>,
+    stop: Arc<AtomicBool>,
+    state: Arc<Mutex<NodeState>>,
+) -> Result<(), SocketError> {
+    // on tap:
+    // check all mapped segments and send data segments
+    loop {
+        // read the word before checking so a publish that lands while we're
+        // checking makes the wait return immediately
+        let value = futex.value();
+        if stop.load(Ordering::Acquire) {
+            break;
+        }
+
+        let inboxes: Vec<(u64, Arc<Mutex<Inbox>>, _)> = {
+            let state = state.lock().unwrap();
+            state
+                .subscriptions
+                .iter()
+                .filter(|(_, sub)| sub.wakeup == Wakeup::Futex)
+                .map(|(id, sub)| (*id, sub.inbox.clone(), executor_of(&state, *id)))
+                .collect()
+        };
+        for (id, inbox, executor) in inboxes {
+            dispatch(executor, Some(id), Box::new(move || deliver(&inbox)));
+        }
+
+        futex.wait(value)?;
+    }
+    return Ok(());
+}
+
+fn send_subscribe(
+    peer: &Peer,
+    id: u64,
+    sub: &SubscriptionState,
+    futex: &Futex,
+) -> Result<(), SocketError> {
+    let fd = match &sub.event {
+        Some(event) => event.as_raw_fd(),
+        None => futex.as_raw_fd(),
+    };
+    let message = ControlMessage::Subscribe {
+        topic: sub.topic.clone(),
+        id: id,
+        wakeup: sub.wakeup,
+        reliable: sub.qos.is_reliable(),
+        latched: sub.qos.latched(),
+    };
+    return peer.send(&message, &[fd]);
+}
+
+// Peers are nodes of other processes, told apart by the connection to their
+// process and their slot in it. Connection 0 is our own process, the other
+// nodes of our context are announced under it.
+fn peer_id(key: u64, slot: u32) -> u64 {
+    return (key << 32) | slot as u64;
+}
+
+// every node of the context, so they can be locked without it
+fn nodes(context: &Mutex<ContextState>) -> Vec<Arc<Mutex<NodeState>>> {
+    return context.lock().unwrap().nodes.values().cloned().collect();
+}
+
+// the node with subscription id, if it's still subscribed
+fn subscriber(context: &Mutex<ContextState>, id: u64) -> Option<Arc<Mutex<NodeState>>> {
+    return nodes(context)
+        .into_iter()
+        .find(|node| node.lock().unwrap().subscriptions.contains_key(&id));
+}
+
+fn add_peer(
+    epoll: &mut Epoll,
+    context: &Mutex<ContextState>,
+    stream: UnixStream,
+) -> Result<(), SocketError> {
+    let clone = match stream.try_clone() {
+        Ok(clone) => Arc::new(Connection::new(clone)),
+        Err(err) => {
+            return Err(SocketError::new(format!("Failed to clone stream: {}", err)));
+        }
+    };
+    let key = epoll.add_stream(stream)?;
+    context.lock().unwrap().streams.insert(key, clone.clone());
+
+    if let Err(err) = send_announcements(context, &clone) {
+        // gone already or can't be told, either way it's no peer of ours
+        epoll.remove(key)?;
+        drop_connection(context, key);
+        return Err(err);
+    }
+    return Ok(());
+}
+
+// lets a new process know what each of our nodes publish, they're added as
+// peers once its nodes reply
+fn send_announcements(
+    context: &Mutex<ContextState>,
+    stream: &Connection,
+) -> Result<(), SocketError> {
+    for node in nodes(context) {
+        let state = node.lock().unwrap();
+        let route = Route {
+            from: state.slot,
+            to: EVERY_NODE,
+        };
+        for publication in state.publications.values() {
+            publication
+                .announcement
+                .message()
+                .send(stream, route, &[])?;
+        }
+    }
+    return Ok(());
+}
+
+// forget every node of the process at the other end of connection key
+fn drop_connection(context: &Mutex<ContextState>, key: u64) {
+    context.lock().unwrap().streams.remove(&key);
+    for node in nodes(context) {
+        let mut peers: Vec<u64> = {
+            let state = node.lock().unwrap();
+            state
+                .peers
+                .keys()
+                .chain(state.announced.keys())
+                .filter(|peer| *peer >> 32 == key)
+                .copied()
+                .collect()
+        };
+        peers.sort();
+        peers.dedup();
+        for peer in peers {
+            drop_peer(&node, peer);
+        }
+    }
+}
+
+// forget everything we know about peer, it's gone or going
+fn drop_peer(state: &Mutex<NodeState>, peer: u64) {
+    let mut inboxes: Vec<(u64, Arc<Mutex<Inbox>>, _)> = Vec::new();
+    {
+        let mut state = state.lock().unwrap();
+        state.peers.remove(&peer);
+        state.announced.remove(&peer);
+        for publication in state.publications.values_mut() {
+            for sub in publication.subscribers.iter() {
+                if let (true, Some(cursor)) = (sub.peer == peer, sub.cursor) {
+                    publication.writer.release_cursor(cursor);
+                    if let Err(err) = publication.room.wake() {
+                        println!("Failed to wake publisher: {}", err);
+                    }
+                }
+            }
+            publication.subscribers.retain(|sub| sub.peer != peer);
+        }
+        for sub in state.subscriptions.values_mut() {
+            sub.requested.retain(|requested| *requested != peer);
+        }
+        for (id, sub) in state.subscriptions.iter() {
+            inboxes.push((*id, sub.inbox.clone(), executor_of(&state, *id)));
+        }
+    }
+
+    // unmap their segments, after delivering whatever they published last
+    for (id, inbox, executor) in inboxes {
+        for source in inbox.lock().unwrap().sources.iter_mut() {
+            if source.peer == peer {
+                source.gone = true;
+            }
+        }
+        dispatch(executor, Some(id), Box::new(move || deliver(&inbox)));
+    }
+}
+
+// takes ownership of the single fd that should have come with packet
+// has subscription id checked, as though something was published to it
+fn wake(state: &NodeState, id: u64) -> Result<(), SocketError> {
+    match state.subscriptions.get(&id).and_then(|sub| sub.event.as_ref()) {
+        Some(event) => {
+            return event.incr();
+        }
+        None => {
+            return state.futex.wake();
+        }
+    }
 }
 
-fn futex_loop() {
-    // on tap:
-    // check all mapped segments and send data segments
-}
+// the shorter of two optional durations, None being forever
+fn shorter(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            return Some(a.min(b));
+        }
+        (a, b) => {
+            return a.or(b);
+        }
+    }
+}
+
+// Has the socket thread deliver to sub and check its deadline and liveliness,
+// once it's matched with a publisher
+fn register(epoll: &mut Epoll, id: u64, sub: &mut SubscriptionState) -> Result<(), SocketError> {
+    if let Some(event) = &sub.event {
+        if !sub.registered {
+            epoll.add_event(id, event.dup()?)?;
+            sub.registered = true;
+        }
+    }
+    if sub.timer.is_none() {
+        if let Some(period) = shorter(sub.qos.deadline, sub.qos.lease_duration) {
+            let timer = TimerFd::new()?;
+            timer.set(period.max(Duration::from_nanos(1)), Duration::ZERO)?;
+            epoll.add_timer(id, timer.dup()?)?;
+            sub.timer = Some(timer);
+        }
+    }
+    return Ok(());
+}
+
+fn take_fd(packet: &mut Packet) -> Result<RawFd, SocketError> {
+    return Ok(take_fds(packet, 1)?[0]);
+}
+
+fn take_fds(packet: &mut Packet, count: usize) -> Result<Vec<RawFd>, SocketError> {
+    let fds = std::mem::take(&mut packet.fds);
+    if fds.len() != count {
+        for fd in fds.iter() {
+            unsafe {
+                libc::close(*fd);
+            }
+        }
+        return Err(SocketError::new(format!(
+            "Expected {} fds with control message, got {}",
+            count,
+            fds.len()
+        )));
+    }
+    return Ok(fds);
+}
+
+fn handle_packet(
+    epoll: &mut Epoll,
+    context: &Mutex<ContextState>,
+    mut packet: Packet,
+) -> Result<(), SocketError> {
+    let (route, message) = match ControlMessage::decode_routed(&packet.bytes) {
+        Ok(decoded) => decoded,
+        Err(err) => {
+            for fd in packet.fds.iter() {
+                unsafe {
+                    libc::close(*fd);
+                }
+            }
+            return Err(err);
+        }
+    };
+
+    // the whole process is leaving
+    if let (ControlMessage::Bye, EVERY_NODE) = (&message, route.from) {
+        drop_connection(context, packet.source);
+        return Ok(());
+    }
+
+    let (stream, nodes) = {
+        let context = context.lock().unwrap();
+        let nodes: Vec<Arc<Mutex<NodeState>>> = context
+            .nodes
+            .iter()
+            .filter(|(slot, _)| route.to == EVERY_NODE || **slot == route.to)
+            .map(|(_, node)| node.clone())
+            .collect();
+        (context.streams.get(&packet.source).cloned(), nodes)
+    };
+    let stream = match (stream, nodes.is_empty()) {
+        (Some(stream), false) => stream,
+        _ => {
+            // the node it's for has left since
+            for fd in packet.fds.iter() {
+                unsafe {
+                    libc::close(*fd);
+                }
+            }
+            return Ok(());
+        }
+    };
+
+    let peer = peer_id(packet.source, route.from);
+    for node in nodes {
+        if !matches!(message, ControlMessage::Bye) {
+            let mut state = node.lock().unwrap();
+            let reply = Route {
+                from: state.slot,
+                to: route.from,
+            };
+            state.peers.entry(peer).or_insert_with(|| Peer {
+                stream: stream.clone(),
+                route: reply,
+            });
+        }
+        handle_message(epoll, &node, peer, message.clone(), &mut packet)?;
+    }
+    return Ok(());
+}
+
+// handles a message from peer for the node with state
+fn handle_message(
+    epoll: &mut Epoll,
+    state: &Mutex<NodeState>,
+    peer: u64,
+    message: ControlMessage,
+    packet: &mut Packet,
+) -> Result<(), SocketError> {
+    match message {
+        ControlMessage::Announce {
+            topic,
+            head_type_name,
+            body_type_name,
+            proto_defs,
+            node,
+            pid,
+            id,
+            announced_at,
+            compatible,
+            qos,
+        } => {
+            let announcement = Announcement {
+                topic: topic,
+                head_type_name: head_type_name,
+                body_type_name: body_type_name,
+                proto_defs: proto_defs,
+                node: node,
+                pid: pid,
+                id: id,
+                announced_at: announced_at,
+                compatible: compatible,
+                qos: *qos,
+            };
+
+            let mut state = state.lock().unwrap();
+            let state = &mut *state;
+            // announced again, in reply to another node's Hello
+            if state.announced.get(&peer).is_some_and(|announced| {
+                return announced.iter().any(|a| a.topic == announcement.topic);
+            }) {
+                return Ok(());
+            }
+            if let Err(err) = schema::parse(&announcement.proto_defs) {
+                println!("Ignoring definitions for {}: {}", announcement.topic, err);
+            } else if !announcement.proto_defs.is_empty() {
+                state.schemas.register(
+                    &[&announcement.head_type_name, &announcement.body_type_name],
+                    &announcement.proto_defs,
+                );
+            }
+
+            state.announced.entry(peer).or_default().push(announcement);
+            let announcement = state.announced[&peer].last().unwrap();
+            if let Some(conflict) = state.conflict(announcement) {
+                println!("Ignoring publisher: {}", conflict);
+                return Ok(());
+            }
+
+            // subscriptions waiting on this topic
+            if let Some(publisher) = state.peers.get(&peer) {
+                for (id, sub) in state.subscriptions.iter_mut() {
+                    if sub.topic != announcement.topic || sub.requested.contains(&peer) {
+                        continue;
+                    }
+                    if let Err(err) = sub.accepts(announcement) {
+                        println!("Not subscribing: {}", err);
+                        continue;
+                    }
+                    send_subscribe(publisher, *id, sub, &state.futex)?;
+                    sub.requested.push(peer);
+                }
+            }
+        }
+        ControlMessage::Subscribe {
+            topic,
+            id,
+            wakeup,
+            reliable,
+            latched,
+        } => {
+            let fd = take_fd(packet)?;
+            let doorbell = match wakeup {
+                Wakeup::Futex => Doorbell::Futex(Arc::new(Futex::from_fd(fd)?)),
+                Wakeup::EventFd => unsafe { Doorbell::EventFd(EventFd::from_raw_fd(fd)) },
+            };
+
+            let mut state = state.lock().unwrap();
+            let state = &mut *state;
+            let (publication, subscriber) =
+                match (state.publications.get_mut(&topic), state.peers.get(&peer)) {
+                    (Some(publication), Some(subscriber)) => (publication, subscriber),
+                    _ => {
+                        return Err(SocketError::new(format!(
+                            "Subscribe to unknown topic {}",
+                            topic
+                        )));
+                    }
+                };
+
+            // keep-all publishers wait on everyone, reliable ones on whoever
+            // asks them to
+            let qos = &publication.announcement.qos;
+            let latched = latched.min(qos.latched());
+            let mut cursor: Option<usize> = None;
+            if qos.history == History::KeepAll || (reliable && qos.is_reliable()) {
+                cursor = publication.writer.claim_cursor(latched);
+                if cursor.is_none() {
+                    println!("Out of cursors for {}, subscriber may miss samples", topic);
+                }
+            }
+            publication.subscribers.push(Subscriber {
+                peer: peer,
+                id: id,
+                doorbell: doorbell,
+                cursor: cursor,
+            });
+            let reply = ControlMessage::Segment {
+                topic: topic,
+                id: id,
+                cursor: cursor,
+                latched: latched,
+                oversized_from: publication.writer.next_sequence(),
+            };
+            let fds = [publication.writer.as_raw_fd(), publication.room.as_raw_fd()];
+            subscriber.send(&reply, &fds)?;
+        }
+        ControlMessage::Segment {
+            topic,
+            id,
+            cursor,
+            latched,
+            oversized_from,
+        } => {
+            let memory = state
+                .lock()
+                .unwrap()
+                .subscriptions
+                .get(&id)
+                .map_or(MemoryOptions::default(), |sub| sub.memory);
+            let fds = take_fds(packet, 2)?;
+            let room = match Futex::from_fd(fds[1]) {
+                Ok(room) => room,
+                Err(err) => {
+                    unsafe {
+                        libc::close(fds[0]);
+                    }
+                    return Err(err);
+                }
+            };
+            let mut reader = SharedSegmentReader::new(fds[0], cursor, latched, &memory)?;
+            if cursor.is_some() {
+                reader.set_room(Arc::new(room));
+            }
+
+            let inbox: Arc<Mutex<Inbox>>;
+            {
+                let mut state = state.lock().unwrap();
+                // the publisher may want its samples to expire sooner than we do
+                let offered = state
+                    .announced
+                    .get(&peer)
+                    .and_then(|announcements| announcements.iter().find(|a| a.topic == topic))
+                    .and_then(|announcement| announcement.qos.lifespan);
+                let sub = match state.subscriptions.get_mut(&id) {
+                    Some(sub) => sub,
+                    None => {
+                        return Err(SocketError::new(format!(
+                            "Segment for unknown subscription to {}",
+                            topic
+                        )));
+                    }
+                };
+                register(epoll, id, sub)?;
+                reader.set_lifespan(shorter(sub.qos.lifespan, offered));
+                reader.set_heads_only(sub.heads_only);
+                inbox = sub.inbox.clone();
+            }
+            {
+                let mut inbox = inbox.lock().unwrap();
+                if inbox.deadline_from == 0 {
+                    inbox.deadline_from = clock_ns(libc::CLOCK_MONOTONIC);
+                }
+                inbox.sources.push(Source {
+                    peer: peer,
+                    reader: reader,
+                    gone: false,
+                    alive: true,
+                    replacement: None,
+                    pending: None,
+                    oversized_from: oversized_from,
+                });
+            }
+
+            // nothing will be published to ring us about the latched samples
+            if latched > 0 {
+                wake(&state.lock().unwrap(), id)?;
+            }
+        }
+        ControlMessage::SegmentOffer { topic, id, cursor } => {
+            let memory = state
+                .lock()
+                .unwrap()
+                .subscriptions
+                .get(&id)
+                .map_or(MemoryOptions::default(), |sub| sub.memory);
+            let reader = SharedSegmentReader::new(take_fd(packet)?, cursor, 0, &memory)?;
+            let inbox = match state.lock().unwrap().subscriptions.get(&id) {
+                Some(sub) => sub.inbox.clone(),
+                None => {
+                    // unsubscribed since
+                    return Ok(());
+                }
+            };
+            {
+                let mut inbox = inbox.lock().unwrap();
+                match inbox.sources.iter_mut().find(|source| source.peer == peer) {
+                    Some(source) => {
+                        // a later offer has everything an earlier one had
+                        source.replacement = Some(reader);
+                    }
+                    None => {
+                        return Err(SocketError::new(format!(
+                            "Segment offer for {} without a segment",
+                            topic
+                        )));
+                    }
+                }
+            }
+            // the sample that outgrew the old segment may have rung us
+            // before we could read it
+            wake(&state.lock().unwrap(), id)?;
+        }
+        ControlMessage::Oversized {
+            topic,
+            id,
+            sequence,
+        } => {
+            let heads_only = state
+                .lock()
+                .unwrap()
+                .subscriptions
+                .get(&id)
+                .is_some_and(|sub| sub.heads_only);
+            let mut sample = read_sealed(take_fd(packet)?, heads_only)?;
+            let inbox = match state.lock().unwrap().subscriptions.get(&id) {
+                Some(sub) => sub.inbox.clone(),
+                None => {
+                    // unsubscribed since
+                    return Ok(());
+                }
+            };
+            {
+                let mut inbox = inbox.lock().unwrap();
+                if let Some(source) = inbox.sources.iter().find(|source| source.peer == peer) {
+                    sample.info.publisher_id = source.reader.publisher_id();
+                }
+                sample.info.sequence = sequence;
+                sample.info.topic_id = topic_id(&topic);
+                inbox.oversized.push_back((peer, sample));
+            }
+            wake(&state.lock().unwrap(), id)?;
+        }
+        ControlMessage::Bye => {
+            drop_peer(state, peer);
+        }
+        ControlMessage::Hello => {
+            let state = state.lock().unwrap();
+            if let Some(newcomer) = state.peers.get(&peer) {
+                for publication in state.publications.values() {
+                    newcomer.send(&publication.announcement.message(), &[])?;
+                }
+            }
+        }
+    }
+    return Ok(());
+}
+
+// event id the socket thread is told about local matches with, subscription
+// ids never get this far
+const LOCAL_MATCHES: u64 = u64::MAX;
+
+fn socket_loop(
+    listener: UnixListener,
+    connections: Vec<UnixStream>,
+    shutdown: EventFd,
+    signals: Option<SignalFd>,
+    liveliness_timer: TimerFd,
+    local_matches: EventFd,
+    context: Arc<Mutex<ContextState>>,
+) -> Result<(), SocketError> {
+    // setup epoll to listen for shutdown and new connections
+    let mut epoll = Epoll::new()?;
+    epoll.add_listener(listener)?;
+    epoll.add_event(0, shutdown)?; // subscriptions use ids from 1 up
+    epoll.add_timer(0, liveliness_timer)?; // and so do their timers
+    epoll.add_event(LOCAL_MATCHES, local_matches)?;
+    if let Some(signals) = signals {
+        epoll.add_signal(signals)?;
+    }
+    for stream in connections {
+        if let Err(err) = add_peer(&mut epoll, &context, stream) {
+            println!("Failed to add peer: {}", err);
+        }
+    }
+
+    // listen on all known sockets
+    loop {
+        match epoll.next() {
+            Ok(DescribedInput::UnixStream(new_stream)) => {
+                if let Err(err) = add_peer(&mut epoll, &context, new_stream) {
+                    println!("Failed to add peer: {}", err);
+                }
+            }
+            Ok(DescribedInput::Packet(packet)) => {
+                if let Err(err) = handle_packet(&mut epoll, &context, packet) {
+                    println!("Failed to handle control message: {}", err);
+                }
+            }
+            Ok(DescribedInput::Event(key, event_id)) => match event_id {
+                0 => {
+                    break;
+                }
+                LOCAL_MATCHES => {
+                    let matched = std::mem::take(&mut context.lock().unwrap().matched_locally);
+                    for id in matched {
+                        // may have been dropped since
+                        let node = match subscriber(&context, id) {
+                            Some(node) => node,
+                            None => {
+                                continue;
+                            }
+                        };
+                        let inbox = match node.lock().unwrap().subscriptions.get_mut(&id) {
+                            Some(sub) => {
+                                register(&mut epoll, id, sub)?;
+                                sub.inbox.clone()
+                            }
+                            None => {
+                                continue;
+                            }
+                        };
+                        let mut inbox = inbox.lock().unwrap();
+                        if inbox.deadline_from == 0 {
+                            inbox.deadline_from = clock_ns(libc::CLOCK_MONOTONIC);
+                        }
+                    }
+                }
+                _ => {
+                    let found = subscriber(&context, event_id).and_then(|node| {
+                        let state = node.lock().unwrap();
+                        let inbox = state.subscriptions.get(&event_id)?.inbox.clone();
+                        return Some((inbox, executor_of(&state, event_id)));
+                    });
+                    match found {
+                        Some((inbox, executor)) => {
+                            let job = Box::new(move || deliver(&inbox));
+                            dispatch(executor, Some(event_id), job);
+                        }
+                        None => {
+                            // rung just before its node left
+                            epoll.remove(key)?;
+                        }
+                    }
+                }
+            },
+            Ok(DescribedInput::Timer(_, 0)) => {
+                for node in nodes(&context) {
+                    let mut state = node.lock().unwrap();
+                    for publication in state.publications.values_mut() {
+                        let qos = &publication.announcement.qos;
+                        if qos.liveliness == Liveliness::Automatic && qos.lease_duration.is_some() {
+                            publication.writer.assert_liveliness();
+                        }
+                    }
+                }
+            }
+            Ok(DescribedInput::Timer(key, id)) => {
+                let node = match subscriber(&context, id) {
+                    Some(node) => node,
+                    None => {
+                        // subscription has been dropped
+                        epoll.remove(key)?;
+                        continue;
+                    }
+                };
+                let executor = executor_of(&node.lock().unwrap(), id);
+                let job = Box::new(move || {
+                    if let Err(err) = check_timers(&node, id) {
+                        println!("Failed to check timers: {}", err);
+                    }
+                });
+                dispatch(executor, None, job);
+            }
+            Ok(DescribedInput::Signal(signo)) => {
+                // same path as an explicit shutdown
+                println!("Received signal {}, shutting down", signo);
+                break;
+            }
+            Ok(DescribedInput::Pending) => {}
+            Ok(DescribedInput::Hangup(key)) => {
+                epoll.remove(key)?;
+                drop_connection(&context, key);
+            }
+            Err(err) => {
+                // TODO(micah) should descriminate more about the errors
+                // TODO(micah) should setup logging
+                println!("Error: {}", err);
+            }
+        }
+    }
+
+    // let peers drop our segments and subscriptions now rather than when
+    // they notice the hangup
+    let context = context.lock().unwrap();
+    let route = Route {
+        from: EVERY_NODE,
+        to: EVERY_NODE,
+    };
+    for stream in context.streams.values() {
+        if let Err(err) = ControlMessage::Bye.send(stream, route, &[]) {
+            println!("Failed to say bye: {}", err);
+        }
+    }
+
+    return Ok(());
+}
+
+fn make_seq_socket_connection(sock_path: &str) -> Result<UnixStream, SocketError> {
+    let fd: RawFd;
+    unsafe {
+        fd = socket(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0);
+        if fd < 0 {
+            return Err(SocketError::new(format!(
+                "Failed to construct socket: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+    }
+
+    //name.sun_family = AF_UNIX;
+    // strncpy(name.sun_path, SOCKET_NAME, sizeof(name.sun_path) - 1);
+    // need 0 at begin, and 0 at end, so only have 12 characters
+    if sock_path.len() > 12 {
+        unsafe {
+            libc::close(fd);
+        }
+        return Err(SocketError::new(format!(
+            "Socket name should be < 12 characters (got {})",
+            sock_path.len()
+        )));
+    }
+
+    let mut sa_data: [libc::c_char; 14] = [0; 14];
+    for (i, c) in sock_path.bytes().enumerate() {
+        sa_data[i + 1] = c as i8;
+    }
+
+    let addr = libc::sockaddr {
+        sa_family: libc::AF_UNIX as u16, // 2 bytes
+        sa_data: sa_data,                // 14 bytes
+    };
+
+    unsafe {
+        let ret = libc::connect(fd, &addr, 16);
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            libc::close(fd);
+            return Err(SocketError::new(format!(
+                "Failed to connect to {}: {}",
+                sock_path, err,
+            )));
+        }
+    }
+
+    unsafe {
+        return Ok(UnixStream::from_raw_fd(fd));
+    }
+}
+
+fn make_seq_socket_listener(sock_path: &str) -> Result<UnixListener, SocketError> {
+    let fd: RawFd;
+    unsafe {
+        fd = socket(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0);
+        if fd < 0 {
+            return Err(SocketError::new("Failed to construct socket".to_string()));
+        }
+    }
+
+    //name.sun_family = AF_UNIX;
+    // strncpy(name.sun_path, SOCKET_NAME, sizeof(name.sun_path) - 1);
+    // need 0 at begin, and 0 at end, so only have 12 characters
+    if sock_path.len() > 12 {
+        return Err(SocketError::new(format!(
+            "Socket name should be < 12 characters (got {})",
+            sock_path.len()
+        )));
+    }
+
+    let mut sa_data: [libc::c_char; 14] = [0; 14];
+    for (i, c) in sock_path.bytes().enumerate() {
+        sa_data[i + 1] = c as i8;
+    }
+    let addr = libc::sockaddr {
+        sa_family: libc::AF_UNIX as u16,
+        sa_data: sa_data,
+    };
+
+    unsafe {
+        let ret = libc::bind(fd, &addr, 16);
+        if ret == -1 {
+            libc::close(fd);
+            return Err(SocketError::new(
+                format!("Failed to bind to {}", ret).to_string(),
+            ));
+        }
+    }
+
+    unsafe {
+        let ret = libc::listen(fd, 20);
+        if ret == -1 {
+            libc::close(fd);
+            return Err(SocketError::new(
+                format!("Failed to listen to {}", ret).to_string(),
+            ));
+        }
+    }
+
+    unsafe {
+        return Ok(UnixListener::from_raw_fd(fd));
+    }
+}
+
+impl NodeState {
+    // everything announced on topic, ours included
+    fn announcements<'a>(&'a self, topic: &'a str) -> impl Iterator<Item = &'a Announcement> {
+        let ours = self.publications.get(topic).map(|p| &p.announcement);
+        let theirs = self.announced.values().flatten();
+        return ours
+            .into_iter()
+            .chain(theirs.filter(move |announcement| announcement.topic == topic));
+    }
+
+    // the earliest announcement of topic, which fixes its type
+    fn topic_type<'a>(&'a self, topic: &'a str) -> Option<&'a Announcement> {
+        return self
+            .announcements(topic)
+            .min_by(|a, b| a.order().cmp(&b.order()));
+    }
+
+    // why announcement's publisher shouldn't be subscribed to, if it shouldn't
+    fn conflict(&self, announcement: &Announcement) -> Option<String> {
+        let first = self.topic_type(&announcement.topic)?;
+        if announcement.compatible || first.same_type(announcement) {
+            return None;
+        }
+        return Some(format!(
+            "{} is published as {}/{} by {} ({}), {} ({}) announced {}/{}",
+            announcement.topic,
+            first.head_type_name,
+            first.body_type_name,
+            first.node,
+            first.pid,
+            announcement.node,
+            announcement.pid,
+            announcement.head_type_name,
+            announcement.body_type_name
+        ));
+    }
+
+    // body type name and definitions of topic's type, falling back to the
+    // registry when its publisher announced the name only
+    pub(crate) fn topic_schema(&self, topic: &str) -> Option<(String, Vec<u8>)> {
+        return self.announced_schema(self.topic_type(topic)?);
+    }
+
+    // the body type and definitions publisher_id announced topic with
+    pub(crate) fn publisher_schema(
+        &self,
+        topic: &str,
+        publisher_id: u64,
+    ) -> Option<(String, Vec<u8>)> {
+        let announcement = self
+            .announcements(topic)
+            .find(|announcement| announcement.id == publisher_id)?;
+        return self.announced_schema(announcement);
+    }
+
+    // body type name and definitions announcement came with, or the registry's
+    fn announced_schema(&self, announcement: &Announcement) -> Option<(String, Vec<u8>)> {
+        let type_name = announcement.body_type_name.clone();
+        if !announcement.proto_defs.is_empty() {
+            return Some((type_name, announcement.proto_defs.clone()));
+        }
+        let proto_defs = self.schemas.get(&type_name)?.clone();
+        return Some((type_name, proto_defs));
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn is_announced(&self, topic: &str) -> bool {
+        return self.publications.contains_key(topic);
+    }
+}
+
+// SampleInfo::topic_id of topic
+pub fn topic_id(topic: &str) -> u64 {
+    return CRC64.checksum(topic.as_bytes());
+}
+
+// What publish() waits on when subscribers hold it up
+pub(crate) struct Blocked {
+    // bumped from seen on as they read
+    pub(crate) room: Arc<Futex>,
+    pub(crate) seen: u32,
+    // how long the publication's reliability lets it wait, none for best
+    // effort
+    pub(crate) max_blocking: Duration,
+}
+
+// Waits up to the publication's max_blocking for subscribers holding it up
+// to catch up
+pub(crate) fn publish(
+    state: &Mutex<NodeState>,
+    topic: &str,
+    head: &[u8],
+    body: &[u8],
+) -> Result<(), SocketError> {
+    let mut deadline: Option<std::time::Instant> = None;
+    loop {
+        let blocked = match try_publish(state, topic, head, body)? {
+            Some(blocked) => blocked,
+            None => {
+                return Ok(());
+            }
+        };
+        let deadline = *deadline.get_or_insert(std::time::Instant::now() + blocked.max_blocking);
+        let now = std::time::Instant::now();
+        if now >= deadline {
+            return Err(not_caught_up(topic));
+        }
+
+        // not holding the lock, so the subscriber holding us up can be
+        // dropped if it goes away
+        let timeout = deadline - now;
+        blocked.room.wait_timeout(blocked.seen, Some(timeout))?;
+    }
+}
+
+pub(crate) fn not_caught_up(topic: &str) -> SocketError {
+    return SocketError::with_kind(
+        ErrorKind::WouldBlock,
+        format!("Subscribers of {} haven't caught up", topic),
+    );
+}
+
+// Publishes unless subscribers hold it up, returns what to wait on for them
+// then
+pub(crate) fn try_publish(
+    state: &Mutex<NodeState>,
+    topic: &str,
+    head: &[u8],
+    body: &[u8],
+) -> Result<Option<Blocked>, SocketError> {
+    let mut guard = state.lock().unwrap();
+    let state = &mut *guard;
+    let publication = match state.publications.get_mut(topic) {
+        Some(publication) => publication,
+        None => {
+            return Err(SocketError::new(format!(
+                "Publish to {} without announcing it",
+                topic
+            )));
+        }
+    };
+
+    let len = head.len() + body.len();
+    if len > publication.writer.max_message_bytes()
+        && len <= publication.announcement.qos.max_segment_sample_bytes
+    {
+        grow_segment(publication, &state.peers, topic, len)?;
+    }
+
+    // read before checking, so whoever makes room after that has bumped it
+    // by the time we wait on it
+    let seen = publication.room.value();
+
+    // subscriptions in our process that have gone away don't hold us up
+    publication
+        .local
+        .retain(|local| !local.queue.lock().unwrap().closed);
+    let depth = publication.announcement.qos.depth();
+    let local_full = publication.local.iter().any(|local| {
+        return local.reliable && local.queue.lock().unwrap().samples.len() >= depth;
+    });
+
+    // too large for the segment, it only takes a sequence there
+    let oversized = len > publication.writer.max_message_bytes()
+        || head.len() > publication.writer.max_head_bytes();
+    if !local_full
+        && !publication
+            .writer
+            .is_full(if oversized { 0 } else { body.len() })
+    {
+        // the same in the segment, the memfd and our process
+        let monotonic_ns = clock_ns(libc::CLOCK_MONOTONIC);
+        let realtime_ns = clock_ns(libc::CLOCK_REALTIME);
+        let sequence: u64;
+        if oversized {
+            let fd = write_sealed(head, body, monotonic_ns, realtime_ns)?;
+            sequence = publication.writer.skip(monotonic_ns);
+            for subscriber in publication.subscribers.iter() {
+                let message = ControlMessage::Oversized {
+                    topic: topic.to_string(),
+                    id: subscriber.id,
+                    sequence: sequence,
+                };
+                if let Some(peer) = state.peers.get(&subscriber.peer) {
+                    if let Err(err) = peer.send(&message, &[fd]) {
+                        println!("Failed to send oversized {} sample: {}", topic, err);
+                    }
+                }
+            }
+            unsafe {
+                libc::close(fd);
+            }
+        } else {
+            sequence = publication
+                .writer
+                .write(head, body, monotonic_ns, realtime_ns)?;
+        }
+        for subscriber in publication.subscribers.iter() {
+            if let Err(err) = subscriber.doorbell.ring() {
+                println!("Failed to notify subscriber of {}: {}", topic, err);
+            }
+        }
+
+        // subscriptions in our process share one copy instead of
+        // reading the segment
+        let latched = publication.announcement.qos.latched();
+        if publication.local.is_empty() && latched == 0 {
+            return Ok(None);
+        }
+        let sample = Arc::new(Sample {
+            head: head.to_vec(),
+            body: body.to_vec(),
+            info: SampleInfo {
+                sequence: sequence,
+                monotonic_ns: monotonic_ns,
+                realtime_ns: realtime_ns,
+                publisher_id: state.id,
+                topic_id: topic_id(topic),
+            },
+        });
+        for local in publication.local.iter() {
+            let mut queue = local.queue.lock().unwrap();
+            if queue.samples.len() >= depth {
+                queue.samples.pop_front();
+                queue.stats.dropped += 1;
+            }
+            queue.written_ns = sample.info.monotonic_ns;
+            queue.samples.push_back(sample.clone());
+        }
+        if latched > 0 {
+            if publication.latched.len() >= latched {
+                publication.latched.pop_front();
+            }
+            publication.latched.push_back(sample);
+        }
+        for local in publication.local.iter() {
+            if let Err(err) = local.doorbell.ring() {
+                println!("Failed to notify subscriber of {}: {}", topic, err);
+            }
+        }
+        return Ok(None);
+    }
+
+    return Ok(Some(Blocked {
+        room: publication.room.clone(),
+        seen: seen,
+        max_blocking: match publication.announcement.qos.reliability {
+            Reliability::Reliable { max_blocking } => max_blocking,
+            Reliability::BestEffort => Duration::ZERO,
+        },
+    }));
+}
+
+// What a publication in our process, announced as announcement, needs to
+// hand sub samples directly. futex is that of sub's node.
+fn local_subscriber(
+    sub: &SubscriptionState,
+    announcement: &Announcement,
+    futex: &Arc<Futex>,
+) -> Result<LocalSubscriber, SocketError> {
+    sub.accepts(announcement)?;
+    let doorbell = match &sub.event {
+        Some(event) => Doorbell::EventFd(event.dup()?),
+        None => Doorbell::Futex(futex.clone()),
+    };
+
+    // same as for a peer's subscription, see Subscribe
+    let qos = &announcement.qos;
+    sub.local.lock().unwrap().lifespan = shorter(sub.qos.lifespan, qos.lifespan);
+    return Ok(LocalSubscriber {
+        queue: sub.local.clone(),
+        doorbell: doorbell,
+        reliable: qos.history == History::KeepAll || (sub.qos.is_reliable() && qos.is_reliable()),
+    });
+}
+
+// Hands local everything publication publishes from now on, after the last
+// latched samples of those it kept for late subscribers
+fn add_local_subscriber(
+    publication: &mut Publication,
+    local: LocalSubscriber,
+    latched: usize,
+) -> Result<(), SocketError> {
+    let skipped = publication.latched.len().saturating_sub(latched);
+    {
+        let mut queue = local.queue.lock().unwrap();
+        queue
+            .samples
+            .extend(publication.latched.iter().skip(skipped).cloned());
+        if local.reliable {
+            queue.rooms.push(Arc::downgrade(&publication.room));
+        }
+    }
+
+    // nothing will be published to ring it about those
+    if skipped < publication.latched.len() {
+        local.doorbell.ring()?;
+    }
+    publication.local.push(local);
+    return Ok(());
+}
+
+// Records what another node of our process publishes as though a peer had
+// announced it, false if it already was
+fn add_sibling_announcement(state: &mut NodeState, slot: u32, announcement: &Announcement) -> bool {
+    let announced = state.announced.entry(peer_id(0, slot)).or_default();
+    if announced.iter().any(|a| a.topic == announcement.topic) {
+        return false;
+    }
+    announced.push(announcement.clone());
+    if !announcement.proto_defs.is_empty() {
+        state.schemas.register(
+            &[&announcement.head_type_name, &announcement.body_type_name],
+            &announcement.proto_defs,
+        );
+    }
+    return true;
+}
+
+// Has the socket thread register subscriptions ids, just matched with
+// publications in our process, as it would on a peer's Segment
+fn add_local_matches(context: &Mutex<ContextState>, ids: &[u64]) -> Result<(), SocketError> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let mut context = context.lock().unwrap();
+    context.matched_locally.extend_from_slice(ids);
+    return context.local_matches.incr();
+}
+
+// Replaces the publication's segment with one that has room for samples of
+// len bytes and offers it to its subscribers
+fn grow_segment(
+    publication: &mut Publication,
+    peers: &HashMap<u64, Peer>,
+    topic: &str,
+    len: usize,
+) -> Result<(), SocketError> {
+    let qos = &publication.announcement.qos;
+    let max_bytes = len
+        .next_power_of_two()
+        .max(2 * publication.writer.max_message_bytes())
+        .min(qos.max_segment_sample_bytes);
+    publication.writer =
+        publication
+            .writer
+            .grow(qos.depth(), qos.depth() * slotsize(max_bytes), max_bytes)?;
+    for subscriber in publication.subscribers.iter() {
+        let message = ControlMessage::SegmentOffer {
+            topic: topic.to_string(),
+            id: subscriber.id,
+            cursor: subscriber.cursor,
+        };
+        if let Some(peer) = peers.get(&subscriber.peer) {
+            if let Err(err) = peer.send(&message, &[publication.writer.as_raw_fd()]) {
+                println!("Failed to offer grown segment of {}: {}", topic, err);
+            }
+        }
+    }
+    return Ok(());
+}
+
+fn join(
+    handle: Option<std::thread::JoinHandle<Result<(), SocketError>>>,
+) -> Result<(), SocketError> {
+    match handle {
+        None => {
+            return Ok(());
+        }
+        Some(handle) => match handle.join() {
+            Ok(result) => {
+                return result;
+            }
+            Err(_) => {
+                return Err(SocketError::new("Node thread panicked".to_string()));
+            }
+        },
+    }
+}
+
+impl Context {
+    pub fn new(config: &ContextConfig) -> Result<Context, SocketError> {
+        // construct socket
+        let mut maybe_listener: Option<UnixListener> = None;
+        let mut self_name: String = Default::default();
+        for i in 0..config.max_nodes {
+            self_name = format!("\0inps.{:02}", i);
+            match make_seq_socket_listener(&self_name) {
+                Err(err) => {
+                    println!("Could not listen on {}: {}", self_name, err);
+                }
+                Ok(out) => {
+                    maybe_listener = Some(out);
+                    break;
+                }
+            }
+        }
+        let listener = match maybe_listener {
+            Some(listener) => listener,
+            None => {
+                return Err(SocketError::new("No available sockets".to_string()));
+            }
+        };
+
+        // connect to neighbors
+        let mut out_connections: Vec<UnixStream> = Default::default();
+        for i in 0..config.max_nodes {
+            let name = format!("\0inps.{:02}", i);
+            if name == self_name {
+                continue;
+            }
+
+            match make_seq_socket_connection(&name) {
+                Err(err) => {
+                    println!("Could not connect to {}: {}", name, err);
+                }
+                Ok(out) => {
+                    out_connections.push(out);
+                }
+            }
+        }
+
+        let state = Arc::new(Mutex::new(ContextState {
+            streams: Default::default(),
+            nodes: Default::default(),
+            next_slot: 0,
+            next_subscription_id: 1,
+            liveliness_timer: TimerFd::new()?,
+            liveliness_period: None,
+            matched_locally: Default::default(),
+            local_matches: EventFd::new()?,
+        }));
+
+        // Construct socket IO thread with
+        // - shutdown event fd so we can turn it off
+        // - shared state for handling control messages
+        // - join handle so we can join when we stop
+        // - signalfd, if requested, which has to be set up before spawning so
+        //   the socket thread inherits the blocked signals
+        let shutdown = EventFd::new()?;
+        let dup_shutdown = shutdown.dup()?;
+        let mut signals: Option<SignalFd> = None;
+        if config.handle_signals {
+            signals = Some(SignalFd::new(&[libc::SIGINT, libc::SIGTERM])?);
+        }
+        let liveliness_timer = state.lock().unwrap().liveliness_timer.dup()?;
+        let local_matches = state.lock().unwrap().local_matches.dup()?;
+        let socket_state = state.clone();
+        let socket_thread = std::thread::spawn(move || -> Result<(), SocketError> {
+            let result = socket_loop(
+                listener,
+                out_connections,
+                dup_shutdown,
+                signals,
+                liveliness_timer,
+                local_matches,
+                socket_state.clone(),
+            );
+            // however it ended, for anyone waiting on our nodes
+            for node in nodes(&socket_state) {
+                if let Err(err) = node.lock().unwrap().stopped.incr() {
+                    println!("Failed to signal shutdown: {}", err);
+                }
+            }
+            return result;
+        });
+
+        return Ok(Context {
+            shared: Arc::new(ContextShared {
+                state: state,
+                socket_shutdown: shutdown,
+                socket_thread_handle: Mutex::new(Some(socket_thread)),
+            }),
+        });
+    }
+
+    // Stops the socket thread, closing all connections, and waits for it to
+    // finish. The context's nodes stop hearing from other processes. Safe to
+    // call more than once.
+    pub fn shutdown(&self) -> Result<(), SocketError> {
+        return self.shared.shutdown();
+    }
+
+    // Blocks until the socket thread has stopped, either through shutdown()
+    // or because a signal arrived (see ContextConfig::handle_signals).
+    pub fn wait_for_shutdown(&self) -> Result<(), SocketError> {
+        return self.shared.wait_for_shutdown();
+    }
+}
+
+impl ContextShared {
+    fn shutdown(&self) -> Result<(), SocketError> {
+        // harmless once the thread has stopped
+        self.socket_shutdown.incr()?;
+        return self.wait_for_shutdown();
+    }
+
+    fn wait_for_shutdown(&self) -> Result<(), SocketError> {
+        let mut handle = self.socket_thread_handle.lock().unwrap();
+        return join(handle.take());
+    }
+}
+
+impl Drop for ContextShared {
+    fn drop(&mut self) {
+        if let Err(err) = self.shutdown() {
+            println!("Error during shutdown: {}", err);
+        }
+    }
+}
+
+impl Node {
+    // A node with a context of its own, see Node::with_context()
+    pub fn new(config: &NodeConfig) -> Result<Node, SocketError> {
+        let context = Context::new(&ContextConfig {
+            max_nodes: config.max_nodes,
+            handle_signals: config.handle_signals,
+        })?;
+        let mut node = Node::with_context(&context, &config.name)?;
+        node.owns_context = true;
+        return Ok(node);
+    }
+
+    // Joins context as name. Its other nodes share our connections and hand
+    // us what they publish directly, as we do them.
+    pub fn with_context(context: &Context, name: &str) -> Result<Node, SocketError> {
+        // construct our notification futex
+        let futex = Arc::new(Futex::new()?);
+
+        let slot = {
+            let mut context = context.shared.state.lock().unwrap();
+            context.next_slot += 1;
+            context.next_slot - 1
+        };
+        let state = Arc::new(Mutex::new(NodeState {
+            futex: futex.clone(),
+            peers: Default::default(),
+            publications: Default::default(),
+            announced: Default::default(),
+            subscriptions: Default::default(),
+            schemas: Default::default(),
+            name: name.to_string(),
+            pid: std::process::id(),
+            id: rand::random(),
+            slot: slot,
+            executor: Weak::new(),
+            default_group: CallbackGroup::mutually_exclusive(),
+            stopped: EventFd::builder().nonblocking(true).cloexec(true).build()?,
+        }));
+
+        // what the other nodes of the context publish, anything they announce
+        // from now on they tell us about themselves
+        let siblings = nodes(&context.shared.state);
+        context
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .nodes
+            .insert(slot, state.clone());
+        for sibling in siblings {
+            let (sibling_slot, announcements) = {
+                let sibling = sibling.lock().unwrap();
+                let announcements: Vec<Announcement> = sibling
+                    .publications
+                    .values()
+                    .map(|publication| publication.announcement.clone())
+                    .collect();
+                (sibling.slot, announcements)
+            };
+            let mut state = state.lock().unwrap();
+            for announcement in announcements.iter() {
+                add_sibling_announcement(&mut state, sibling_slot, announcement);
+            }
+        }
+
+        // and what other processes announced before we joined
+        {
+            let context = context.shared.state.lock().unwrap();
+            let route = Route {
+                from: slot,
+                to: EVERY_NODE,
+            };
+            for stream in context.streams.values() {
+                if let Err(err) = ControlMessage::Hello.send(stream, route, &[]) {
+                    println!("Failed to say hello: {}", err);
+                }
+            }
+        }
+
+        // Construct the futex thread that delivers to futex subscriptions
+        let futex_stop = Arc::new(AtomicBool::new(false));
+        let futex_thread = {
+            let futex = futex.clone();
+            let stop = futex_stop.clone();
+            let state = state.clone();
+            std::thread::spawn(move || -> Result<(), SocketError> {
+                return futex_loop(futex, stop, state);
+            })
+        };
+
+        return Ok(Node {
+            state: state,
+            context: context.clone(),
+            owns_context: false,
+            futex: futex,
+            futex_stop: futex_stop,
+            futex_thread_handle: Some(futex_thread),
+        });
+    }
+
+    // Leaves the context and stops delivering, also stopping the context if
+    // the node has it to itself. Safe to call more than once.
+    pub fn shutdown(&mut self) -> Result<(), SocketError> {
+        self.stop()?;
+        if self.owns_context {
+            return self.context.shutdown();
+        }
+        return Ok(());
+    }
+
+    // Blocks until the node's context has shut down, either through
+    // shutdown() or because a signal arrived (see NodeConfig::handle_signals).
+    pub fn wait_for_shutdown(&mut self) -> Result<(), SocketError> {
+        let result = self.context.wait_for_shutdown();
+        self.stop()?;
+        return result;
+    }
+
+    // leaves the context, then stops the futex thread
+    fn stop(&mut self) -> Result<(), SocketError> {
+        self.leave();
+        self.state.lock().unwrap().stopped.incr()?;
+        if self.futex_thread_handle.is_some() {
+            self.futex_stop.store(true, Ordering::Release);
+            self.futex.wake()?;
+        }
+        return join(self.futex_thread_handle.take());
+    }
+
+    // Says bye to our peers and the other nodes of the context, which forget
+    // everything we published and stop handing samples to our subscriptions
+    fn leave(&mut self) {
+        let slot = self.state.lock().unwrap().slot;
+        {
+            let mut context = self.context.shared.state.lock().unwrap();
+            if context.nodes.remove(&slot).is_none() {
+                return;
+            }
+            let route = Route {
+                from: slot,
+                to: EVERY_NODE,
+            };
+            for stream in context.streams.values() {
+                if let Err(err) = ControlMessage::Bye.send(stream, route, &[]) {
+                    println!("Failed to say bye: {}", err);
+                }
+            }
+        }
+        for sibling in nodes(&self.context.shared.state) {
+            drop_peer(&sibling, peer_id(0, slot));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.peers.clear();
+        for publication in state.publications.values_mut() {
+            publication.local.clear();
+        }
+        for sub in state.subscriptions.values() {
+            sub.local.lock().unwrap().close();
+        }
+    }
+
+    pub fn announce(
+        &self,
+        topic: &str,
+        head_type_name: &str,
+        body_type_name: &str,
+        proto_defs: &[u8],
+    ) -> Result<(), SocketError> {
+        return self.announce_with(
+            topic,
+            head_type_name,
+            body_type_name,
+            proto_defs,
+            &AnnounceConfig::default(),
+        );
+    }
+
+    // Fails with SchemaMismatch if topic has already been announced with
+    // different type names, unless config says we're compatible
+    pub fn announce_with(
+        &self,
+        topic: &str,
+        head_type_name: &str,
+        body_type_name: &str,
+        proto_defs: &[u8],
+        config: &AnnounceConfig,
+    ) -> Result<(), SocketError> {
+        let mut state = self.state.lock().unwrap();
+        if state.publications.contains_key(topic) {
+            return Err(SocketError::new(format!("Already announced {}", topic)));
+        }
+
+        if config.qos.latched() > config.qos.depth() {
+            return Err(SocketError::with_kind(
+                ErrorKind::IncompatibleQos,
+                format!(
+                    "{} keeps {} samples for late subscribers but only has room for {}",
+                    topic,
+                    config.qos.latched(),
+                    config.qos.depth()
+                ),
+            ));
+        }
+
+        // proto_defs, when given, is a FileDescriptorSet defining the types
+        let type_names = [head_type_name, body_type_name];
+        if !proto_defs.is_empty() {
+            let set = schema::parse(proto_defs)?;
+            for type_name in type_names.iter().filter(|name| !name.is_empty()) {
+                if schema::find_message(&set, type_name).is_none() {
+                    return Err(SocketError::new(format!(
+                        "Definitions for {} don't contain {}",
+                        topic, type_name
+                    )));
+                }
+            }
+        }
+
+        let announced_at = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+        {
+            Ok(since) => since.as_nanos() as u64,
+            Err(_) => 0,
+        };
+        let announcement = Announcement {
+            topic: topic.to_string(),
+            head_type_name: head_type_name.to_string(),
+            body_type_name: body_type_name.to_string(),
+            proto_defs: proto_defs.to_vec(),
+            node: state.name.clone(),
+            pid: state.pid,
+            id: state.id,
+            announced_at: announced_at,
+            compatible: config.compatible,
+            qos: config.qos,
+        };
+        // too large for a control message, so no peer would ever hear of it
+        let message = announcement.message();
+        message.check_size()?;
+
+        if let Some(first) = state.topic_type(topic) {
+            if let Some(conflict) = state.conflict(&announcement) {
+                return Err(SocketError::with_kind(ErrorKind::SchemaMismatch, conflict));
+            }
+            if !first.same_type(&announcement)
+                && !first.proto_defs.is_empty()
+                && !proto_defs.is_empty()
+            {
+                schema::check_compatible(
+                    &schema::parse(&first.proto_defs)?,
+                    &first.body_type_name,
+                    &schema::parse(proto_defs)?,
+                    body_type_name,
+                )?;
+            }
+        }
+        if !proto_defs.is_empty() {
+            state.schemas.register(&type_names, proto_defs);
+        }
+
+        let route = Route {
+            from: state.slot,
+            to: EVERY_NODE,
+        };
+        for stream in self.context.shared.state.lock().unwrap().streams.values() {
+            if let Err(err) = message.send(stream, route, &[]) {
+                println!("Failed to announce {}: {}", topic, err);
+            }
+        }
+
+        // unless told how large, the segment starts out for small samples
+        // and grows as larger ones are published
+        let qos = &config.qos;
+        let (segment_bytes, max_bytes) = match qos.segment_bytes {
+            Some(segment_bytes) => (segment_bytes, qos.max_segment_sample_bytes),
+            None => {
+                let max_bytes = qos.max_segment_sample_bytes.min(INITIAL_SAMPLE_BYTES);
+                (qos.depth() * slotsize(max_bytes), max_bytes)
+            }
+        };
+        let mut writer = SharedSegmentWriter::new(
+            qos.depth(),
+            segment_bytes,
+            max_bytes,
+            qos.max_head_bytes,
+            &config.memory,
+        )?;
+        writer.set_origin(state.id, topic_id(topic));
+        writer.assert_liveliness();
+        if let (Liveliness::Automatic, Some(lease)) =
+            (config.qos.liveliness, config.qos.lease_duration)
+        {
+            let period = (lease / 3).max(Duration::from_millis(1));
+            let mut context = self.context.shared.state.lock().unwrap();
+            if context.liveliness_period.is_none_or(|current| period < current) {
+                context.liveliness_timer.set(period, period)?;
+                context.liveliness_period = Some(period);
+            }
+        }
+        let publication = Publication {
+            writer: writer,
+            announcement: announcement,
+            subscribers: Default::default(),
+            local: Default::default(),
+            latched: Default::default(),
+            room: Arc::new(Futex::new()?),
+        };
+        state.publications.insert(topic.to_string(), publication);
+
+        // our own subscriptions waiting on this topic
+        let mut matched: Vec<u64> = Vec::new();
+        {
+            let state = &mut *state;
+            let publication = state.publications.get_mut(topic).unwrap();
+            for (id, sub) in state.subscriptions.iter() {
+                if sub.topic != topic {
+                    continue;
+                }
+                match local_subscriber(sub, &publication.announcement, &state.futex) {
+                    Ok(local) => {
+                        add_local_subscriber(publication, local, sub.qos.latched())?;
+                        matched.push(*id);
+                    }
+                    Err(err) => {
+                        println!("Not subscribing: {}", err);
+                    }
+                }
+            }
+        }
+        let slot = state.slot;
+        let announcement = state.publications[topic].announcement.clone();
+        drop(state);
+
+        // and those of the other nodes of our context, which hear about it
+        // from us rather than a peer
+        let mut locals: Vec<(LocalSubscriber, usize)> = Vec::new();
+        for sibling in nodes(&self.context.shared.state) {
+            let mut sibling = sibling.lock().unwrap();
+            if sibling.slot == slot || !add_sibling_announcement(&mut sibling, slot, &announcement)
+            {
+                continue;
+            }
+            if let Some(conflict) = sibling.conflict(&announcement) {
+                println!("Ignoring publisher: {}", conflict);
+                continue;
+            }
+            for (id, sub) in sibling.subscriptions.iter() {
+                if sub.topic != topic {
+                    continue;
+                }
+                match local_subscriber(sub, &announcement, &sibling.futex) {
+                    Ok(local) => {
+                        locals.push((local, sub.qos.latched()));
+                        matched.push(*id);
+                    }
+                    Err(err) => {
+                        println!("Not subscribing: {}", err);
+                    }
+                }
+            }
+        }
+        if let Some(publication) = self.state.lock().unwrap().publications.get_mut(topic) {
+            for (local, latched) in locals {
+                add_local_subscriber(publication, local, latched)?;
+            }
+        }
+        return add_local_matches(&self.context.shared.state, &matched);
+    }
+
+    pub fn publish(&self, topic: &str, head: &[u8], body: &[u8]) -> Result<(), SocketError> {
+        return publish(&self.state, topic, head, body);
+    }
+
+    pub fn subscribe<F>(&self, topic: &str, cb: F) -> Result<(), SocketError>
+    where
+        F: Fn(&[u8], &[u8]) + Send + 'static,
+    {
+        return self.subscribe_with(topic, &SubscriptionConfig::default(), cb);
+    }
 
-fn socket_loop(
-    listener: UnixListener,
-    shutdown: EventFd,
-    sender: Sender<Packet>,
-) -> Result<(), SocketError> {
-    // setup epoll to listen for shutdown and new connections
-    let mut epoll = Epoll::new()?;
-    epoll.add_listener(listener)?;
-    epoll.add_event(0, shutdown)?; // TODO event identifier might be useful for multiple
+    pub fn subscribe_with<F>(
+        &self,
+        topic: &str,
+        config: &SubscriptionConfig,
+        cb: F,
+    ) -> Result<(), SocketError>
+    where
+        F: Fn(&[u8], &[u8]) + Send + 'static,
+    {
+        let callback = move |sample: &Sample| cb(&sample.head, &sample.body);
+        self.add_subscription(topic, None, config, Some(Box::new(callback)))?;
+        return Ok(());
+    }
 
-    // listen on all known sockets
-    loop {
-        match epoll.next() {
-            Ok(DescribedInput::UnixStream(new_stream)) => {
-                epoll.add_stream(new_stream)?;
+    // Like subscribe_with(), but the callback also gets what inps stamped on
+    // the sample
+    pub fn subscribe_with_info<F>(
+        &self,
+        topic: &str,
+        config: &SubscriptionConfig,
+        cb: F,
+    ) -> Result<(), SocketError>
+    where
+        F: Fn(&SampleInfo, &[u8], &[u8]) + Send + 'static,
+    {
+        let callback = move |sample: &Sample| cb(&sample.info, &sample.head, &sample.body);
+        self.add_subscription(topic, None, config, Some(Box::new(callback)))?;
+        return Ok(());
+    }
+
+    // Tells subscribers of topic that we're alive without publishing, for
+    // Liveliness::Manual
+    pub fn assert_liveliness(&self, topic: &str) -> Result<(), SocketError> {
+        let mut state = self.state.lock().unwrap();
+        match state.publications.get_mut(topic) {
+            Some(publication) => {
+                publication.writer.assert_liveliness();
+                return Ok(());
             }
-            Ok(DescribedInput::Packet(packet)) => match sender.send(packet) {
-                Ok(_) => {}
-                Err(err) => {
-                    println!(
-                        "Failed to enqueue new data, leaving socket loop, err: {}",
-                        err
-                    );
-                    break;
-                }
-            },
-            Ok(DescribedInput::Event(event_id)) => match event_id {
-                0 => {
-                    break;
-                }
-                _ => {
-                    return Err(SocketError::new(format!(
-                        "Received unexpected event id: {}",
-                        event_id
-                    )));
-                }
-            },
-            Err(err) => {
-                // TODO(micah) should descriminate more about the errors
-                // TODO(micah) should setup logging
-                println!("Error: {}", err);
+            None => {
+                return Err(SocketError::new(format!(
+                    "Asserting liveliness of {} without announcing it",
+                    topic
+                )));
             }
         }
     }
 
-    return Ok(());
-}
+    // readable once the node or its context has shut down, see WaitSet
+    pub(crate) fn stopped(&self) -> Result<EventFd, SocketError> {
+        return self.state.lock().unwrap().stopped.dup();
+    }
 
-fn make_seq_socket_connection(sock_path: &str) -> Result<UnixStream, SocketError> {
-    let fd: RawFd;
-    unsafe {
-        fd = socket(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0);
-        if fd < 0 {
-            return Err(SocketError::new(format!(
-                "Failed to construct socket: {}",
-                std::io::Error::last_os_error()
-            )));
+    // see Executor::add_node()
+    pub(crate) fn set_executor(&self, executor: Weak<ExecutorQueue>) {
+        self.state.lock().unwrap().executor = executor;
+    }
+
+    // see Executor::remove_node(), leaves whichever we've been added to since
+    pub(crate) fn unset_executor(&self, executor: &Arc<ExecutorQueue>) {
+        let mut state = self.state.lock().unwrap();
+        if std::ptr::eq(state.executor.as_ptr(), Arc::as_ptr(executor)) {
+            state.executor = Weak::new();
         }
     }
 
-    //name.sun_family = AF_UNIX;
-    // strncpy(name.sun_path, SOCKET_NAME, sizeof(name.sun_path) - 1);
-    // need 0 at begin, and 0 at end, so only have 12 characters
-    if sock_path.len() > 12 {
-        return Err(SocketError::new(format!(
-            "Socket name should be < 12 characters (got {})",
-            sock_path.len()
-        )));
+    // Stamped on everything this node publishes as SampleInfo::publisher_id
+    pub fn id(&self) -> u64 {
+        return self.state.lock().unwrap().id;
     }
 
-    let mut sa_data: [libc::c_char; 14] = [0; 14];
-    for (i, c) in sock_path.bytes().enumerate() {
-        sa_data[i + 1] = c as i8;
+    // Every topic announced in the domain as far as we know, with its type
+    // and publishers. Publishers whose types conflict are flagged.
+    pub fn topics(&self) -> Vec<TopicInfo> {
+        let state = self.state.lock().unwrap();
+        let mut names: Vec<&str> = state
+            .publications
+            .keys()
+            .map(|topic| topic.as_str())
+            .chain(state.announced.values().flatten().map(|a| a.topic.as_str()))
+            .collect();
+        names.sort();
+        names.dedup();
+
+        let mut topics: Vec<TopicInfo> = Vec::new();
+        for topic in names {
+            let first = state.topic_type(topic).unwrap();
+            let mut publishers: Vec<PublisherInfo> = state
+                .announcements(topic)
+                .map(|announcement| PublisherInfo {
+                    node: announcement.node.clone(),
+                    pid: announcement.pid,
+                    head_type_name: announcement.head_type_name.clone(),
+                    body_type_name: announcement.body_type_name.clone(),
+                    compatible: announcement.compatible,
+                    qos: announcement.qos,
+                    conflict: state.conflict(announcement).is_some(),
+                })
+                .collect();
+            publishers.sort_by(|a, b| (&a.node, a.pid).cmp(&(&b.node, b.pid)));
+            topics.push(TopicInfo {
+                topic: topic.to_string(),
+                head_type_name: first.head_type_name.clone(),
+                body_type_name: first.body_type_name.clone(),
+                publishers: publishers,
+            });
+        }
+        return topics;
     }
 
-    let addr = libc::sockaddr {
-        sa_family: libc::AF_UNIX as u16, // 2 bytes
-        sa_data: sa_data,                // 14 bytes
-    };
+    // The serialized FileDescriptorSet defining type_name, as announced by
+    // whichever node in the domain we heard it from first
+    pub fn schema(&self, type_name: &str) -> Option<Vec<u8>> {
+        return self.state.lock().unwrap().schemas.get(type_name).cloned();
+    }
 
-    unsafe {
-        let ret = libc::connect(fd, &addr, 16);
-        if ret < 0 {
-            return Err(SocketError::new(format!(
-                "Failed to connect to {}: {}",
-                sock_path,
-                std::io::Error::last_os_error(),
-            )));
+    // Subscribes without a callback, samples are taken from the returned
+    // Subscription. Dropping it unsubscribes.
+    pub fn subscription(&self, topic: &str) -> Result<Subscription, SocketError> {
+        return self.subscription_with(topic, &SubscriptionConfig::default());
+    }
+
+    // config.wakeup is ignored, a Subscription is always woken through its
+    // eventfd
+    pub fn subscription_with(
+        &self,
+        topic: &str,
+        config: &SubscriptionConfig,
+    ) -> Result<Subscription, SocketError> {
+        let config = SubscriptionConfig {
+            wakeup: Wakeup::EventFd,
+            ..config.clone()
+        };
+        let (id, event, inbox) = self.add_subscription(topic, None, &config, None)?;
+        return Ok(Subscription {
+            id: id,
+            event: event.unwrap(),
+            inbox: inbox,
+            state: Arc::downgrade(&self.state),
+            #[cfg(feature = "async")]
+            ready: None,
+            #[cfg(feature = "async")]
+            stopped: None,
+        });
+    }
+
+    // returns the subscription id, a dup of its eventfd for subscriptions
+    // without a callback, and its inbox
+    pub(crate) fn add_subscription(
+        &self,
+        topic: &str,
+        type_names: Option<(String, String)>,
+        config: &SubscriptionConfig,
+        callback: Option<Callback>,
+    ) -> Result<(u64, Option<EventFd>, Arc<Mutex<Inbox>>), SocketError> {
+        let wakeup = config.wakeup;
+        let schema = match &config.schema {
+            Some(schema) => {
+                let set = schema::parse(&schema.proto_defs)?;
+                if schema::find_message(&set, &schema.type_name).is_none() {
+                    return Err(SocketError::new(format!(
+                        "Definitions for {} don't contain {}",
+                        topic, schema.type_name
+                    )));
+                }
+                Some((schema.type_name.clone(), set))
+            }
+            None => None,
+        };
+
+        let mut event: Option<EventFd> = None;
+        let mut owner_event: Option<EventFd> = None;
+        if wakeup == Wakeup::EventFd {
+            let new_event = EventFd::builder().nonblocking(true).cloexec(true).build()?;
+            if callback.is_none() {
+                owner_event = Some(new_event.dup()?);
+            }
+            event = Some(new_event);
+        }
+
+        let local: Arc<Mutex<LocalQueue>> = Default::default();
+        let inbox = Arc::new(Mutex::new(Inbox {
+            sources: Default::default(),
+            callback: callback,
+            on_loss: config.on_loss.clone(),
+            deadline: config.qos.deadline,
+            on_deadline_missed: config.on_deadline_missed.clone(),
+            lease_duration: config.qos.lease_duration,
+            on_liveliness_changed: config.on_liveliness_changed.clone(),
+            deadline_from: 0,
+            deadlines_missed: 0,
+            oversized: Default::default(),
+            oversized_received: 0,
+            retired: Default::default(),
+            time_ordered: config.time_ordered,
+            heads_only: config.heads_only,
+            local: local.clone(),
+            local_dropped: 0,
+            local_pending: None,
+        }));
+        let mut sub = SubscriptionState {
+            topic: topic.to_string(),
+            type_names: type_names,
+            schema: schema,
+            compatible: config.compatible,
+            qos: config.qos,
+            memory: config.memory,
+            heads_only: config.heads_only,
+            wakeup: wakeup,
+            event: event,
+            registered: owner_event.is_some(),
+            requested: Default::default(),
+            inbox: inbox.clone(),
+            timer: None,
+            local: local,
+            group: config.callback_group.clone(),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let mut publishers: Vec<(u64, &Announcement)> = state
+            .announced
+            .iter()
+            .flat_map(|(peer, announcements)| announcements.iter().map(|a| (*peer, a)))
+            .filter(|(_, announcement)| announcement.topic == topic)
+            .filter(|(_, announcement)| state.conflict(announcement).is_none())
+            .collect();
+        if let Some(first) = state.topic_type(topic) {
+            sub.accepts_type(first)?;
+        }
+        for (_, announcement) in publishers.iter() {
+            sub.accepts_type(announcement)?;
+        }
+        let mut ours = state.publications.get(topic).map(|p| &p.announcement);
+        ours = ours.filter(|a| state.conflict(a).is_none());
+        if let Some(announcement) = ours {
+            sub.accepts_type(announcement)?;
         }
+
+        // the same as when they announce later, see Announce
+        publishers.retain(|(_, announcement)| sub.reports_offered(announcement));
+        ours = ours.filter(|announcement| sub.reports_offered(announcement));
+
+        // ask anyone that already publishes topic, the rest get asked when
+        // their announcement shows up
+        let id = {
+            let mut context = self.context.shared.state.lock().unwrap();
+            context.next_subscription_id += 1;
+            context.next_subscription_id - 1
+        };
+        for (peer, _) in publishers.iter() {
+            if let Some(publisher) = state.peers.get(peer) {
+                send_subscribe(publisher, id, &sub, &state.futex)?;
+                sub.requested.push(*peer);
+            }
+        }
+
+        // publications in our process hand samples over directly instead,
+        // those of the other nodes once we've let go of our state
+        let mut locals: Vec<(u32, LocalSubscriber)> = Vec::new();
+        for (peer, announcement) in publishers.iter() {
+            if *peer >> 32 == 0 {
+                let local = local_subscriber(&sub, announcement, &state.futex)?;
+                locals.push((*peer as u32, local));
+            }
+        }
+        let mut matched = !locals.is_empty();
+        if ours.is_some() {
+            let state = &mut *state;
+            let publication = state.publications.get_mut(topic).unwrap();
+            let local = local_subscriber(&sub, &publication.announcement, &state.futex)?;
+            add_local_subscriber(publication, local, sub.qos.latched())?;
+            matched = true;
+        }
+        let latched = sub.qos.latched();
+        state.subscriptions.insert(id, sub);
+        drop(state);
+
+        for (slot, local) in locals {
+            let sibling = match self.context.shared.state.lock().unwrap().nodes.get(&slot) {
+                Some(sibling) => sibling.clone(),
+                None => {
+                    // left since
+                    continue;
+                }
+            };
+            let mut sibling = sibling.lock().unwrap();
+            if let Some(publication) = sibling.publications.get_mut(topic) {
+                add_local_subscriber(publication, local, latched)?;
+            }
+        }
+        if matched {
+            add_local_matches(&self.context.shared.state, &[id])?;
+        }
+        return Ok((id, owner_event, inbox));
     }
+}
 
-    unsafe {
-        return Ok(UnixStream::from_raw_fd(fd));
+impl Drop for Node {
+    fn drop(&mut self) {
+        if let Err(err) = self.shutdown() {
+            println!("Error during shutdown: {}", err);
+        }
     }
 }
 
-fn make_seq_socket_listener(sock_path: &str) -> Result<UnixListener, SocketError> {
-    let fd: RawFd;
-    unsafe {
-        fd = socket(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0);
-        if fd < 0 {
-            return Err(SocketError::new("Failed to construct socket".to_string()));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::os::unix::thread::JoinHandleExt;
+    use std::time::Instant;
+
+    fn node(name: &str) -> Node {
+        return Node::new(&NodeConfig {
+            name: name.to_string(),
+            max_nodes: 16,
+            handle_signals: false,
+        })
+        .unwrap();
+    }
+
+    // publishes until sub takes something, or gives up after a few seconds
+    fn first_sample(publisher: &Node, topic: &str, sub: &Subscription) -> Option<Sample> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            publisher.publish(topic, b"head", b"body").unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+            if let Some(sample) = sub.take() {
+                return Some(sample);
+            }
         }
+        return None;
     }
 
-    //name.sun_family = AF_UNIX;
-    // strncpy(name.sun_path, SOCKET_NAME, sizeof(name.sun_path) - 1);
-    // need 0 at begin, and 0 at end, so only have 12 characters
-    if sock_path.len() > 12 {
-        return Err(SocketError::new(format!(
-            "Socket name should be < 12 characters (got {})",
-            sock_path.len()
-        )));
+    #[test]
+    fn peer_hanging_up_during_handshake() {
+        let a = node("handshake_a");
+        a.announce("/handshake", "", "X", b"").unwrap();
+
+        // gone before we get to tell it what we publish
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        drop(theirs);
+        let key = ours.as_raw_fd() as u64;
+        let mut epoll = Epoll::new().unwrap();
+        let context = &a.context.shared.state;
+        assert!(add_peer(&mut epoll, context, ours).is_err());
+        assert!(!context.lock().unwrap().streams.contains_key(&key));
+        assert!(epoll.remove(key).is_err());
+
+        // and everyone else still hears from us
+        let b = node("handshake_b");
+        let sub = b.subscription("/handshake").unwrap();
+        assert!(first_sample(&a, "/handshake", &sub).is_some());
     }
 
-    let mut sa_data: [libc::c_char; 14] = [0; 14];
-    for (i, c) in sock_path.bytes().enumerate() {
-        sa_data[i + 1] = c as i8;
+    #[test]
+    fn announcement_too_large_to_send() {
+        let set = prost_types::FileDescriptorSet {
+            file: vec![prost_types::FileDescriptorProto {
+                name: Some("x".repeat(crate::protocol::MAX_MESSAGE_BYTES)),
+                message_type: vec![prost_types::DescriptorProto {
+                    name: Some("Big".to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+        let proto_defs = prost::Message::encode_to_vec(&set);
+        let a = node("oversized");
+        let result = a.announce("/oversized", "", "Big", &proto_defs);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Protocol);
+        let state = a.state.lock().unwrap();
+        assert!(!state.publications.contains_key("/oversized"));
+        assert!(state.schemas.get("Big").is_none());
     }
-    let addr = libc::sockaddr {
-        sa_family: libc::AF_UNIX as u16,
-        sa_data: sa_data,
-    };
 
-    unsafe {
-        let ret = libc::bind(fd, &addr, 16);
-        if ret == -1 {
-            libc::close(fd);
-            return Err(SocketError::new(
-                format!("Failed to bind to {}", ret).to_string(),
-            ));
+    #[test]
+    fn oversized_samples_keep_their_place() {
+        let a = node("oversized_order_a");
+        let b = node("oversized_order_b");
+        let config = AnnounceConfig {
+            qos: QosProfile {
+                max_segment_sample_bytes: 64,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        a.announce_with("/oversized_order", "", "X", b"", &config)
+            .unwrap();
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let received = bodies.clone();
+        let callback = move |_: &SampleInfo, _: &[u8], body: &[u8]| {
+            received.lock().unwrap().push(body.len());
+        };
+        b.subscribe_with_info("/oversized_order", &Default::default(), callback)
+            .unwrap();
+        let sub = b.subscription("/oversized_order").unwrap();
+        first_sample(&a, "/oversized_order", &sub).unwrap();
+
+        for round in 0..20 {
+            bodies.lock().unwrap().clear();
+            a.publish("/oversized_order", b"", &[round; 1]).unwrap();
+            a.publish("/oversized_order", b"", &[round; 1000]).unwrap();
+            a.publish("/oversized_order", b"", &[round; 2]).unwrap();
+            let start = Instant::now();
+            while bodies.lock().unwrap().len() < 3 && start.elapsed() < Duration::from_secs(5) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(*bodies.lock().unwrap(), [1, 1000, 2]);
         }
     }
 
-    unsafe {
-        let ret = libc::listen(fd, 20);
-        if ret == -1 {
-            libc::close(fd);
-            return Err(SocketError::new(
-                format!("Failed to listen to {}", ret).to_string(),
-            ));
+    #[test]
+    fn oversized_samples_from_before_subscribing_are_passed() {
+        let a = node("oversized_latched_a");
+        let qos = QosProfile {
+            max_segment_sample_bytes: 64,
+            durability: crate::qos::Durability::TransientLocal { depth: 4 },
+            ..Default::default()
+        };
+        let config = AnnounceConfig {
+            qos: qos,
+            ..Default::default()
+        };
+        a.announce_with("/oversized_latched", "", "X", b"", &config)
+            .unwrap();
+        a.publish("/oversized_latched", b"", &[0; 1]).unwrap();
+        a.publish("/oversized_latched", b"", &[0; 1000]).unwrap();
+        a.publish("/oversized_latched", b"", &[0; 2]).unwrap();
+
+        let b = node("oversized_latched_b");
+        let config = SubscriptionConfig {
+            qos: qos,
+            ..Default::default()
+        };
+        let sub = b.subscription_with("/oversized_latched", &config).unwrap();
+        let mut lengths: Vec<usize> = Vec::new();
+        let start = Instant::now();
+        while lengths.len() < 3 && start.elapsed() < Duration::from_secs(5) {
+            match sub.take() {
+                Some(sample) => lengths.push(sample.body.len()),
+                None => std::thread::sleep(Duration::from_millis(10)),
+            }
+            if lengths == [1, 2] {
+                a.publish("/oversized_latched", b"", &[0; 3]).unwrap();
+            }
         }
+        assert_eq!(lengths, [1, 2, 3]);
     }
 
-    unsafe {
-        return Ok(UnixListener::from_raw_fd(fd));
-    }
-}
+    #[test]
+    fn expired_samples_are_skipped_for_late_subscribers() {
+        let a = node("lifespan_a");
+        let qos = QosProfile {
+            durability: crate::qos::Durability::TransientLocal { depth: 2 },
+            lifespan: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let config = AnnounceConfig {
+            qos: qos,
+            ..Default::default()
+        };
+        a.announce_with("/lifespan", "", "X", b"", &config).unwrap();
+        a.publish("/lifespan", b"", b"stale").unwrap();
+        std::thread::sleep(Duration::from_millis(1100));
+        a.publish("/lifespan", b"", b"fresh").unwrap();
 
-impl Node {
-    pub fn new(config: &NodeConfig) -> Result<Node, SocketError> {
-        // construct socket
-        let mut maybe_listener: Option<UnixListener> = None;
-        let mut self_name: String = Default::default();
-        for i in 0..config.max_nodes {
-            self_name = format!("\0inps.{:02}", i);
-            match make_seq_socket_listener(&self_name) {
-                Err(err) => {
-                    println!("Could not listen on {}: {}", self_name, err);
-                }
-                Ok(out) => {
-                    maybe_listener = Some(out);
+        // whether read from the segment or handed over in the node
+        let b = node("lifespan_b");
+        let config = SubscriptionConfig {
+            qos: qos,
+            ..Default::default()
+        };
+        let remote = b.subscription_with("/lifespan", &config).unwrap();
+        let local = a.subscription_with("/lifespan", &config).unwrap();
+        for sub in [&remote, &local] {
+            let start = Instant::now();
+            let sample = loop {
+                if let Some(sample) = sub.take() {
+                    break sample;
                 }
-            }
+                assert!(start.elapsed() < Duration::from_secs(5));
+                std::thread::sleep(Duration::from_millis(1));
+            };
+            assert_eq!(sample.body, b"fresh");
+            assert!(sub.take().is_none());
+            let stats = sub.stats();
+            assert_eq!((stats.received, stats.expired), (1, 1));
         }
-        if self_name.len() == 0 {
-            return Err(SocketError::new(
-                format!("No available sockets").to_string(),
-            ));
+    }
+
+    #[test]
+    fn heads_only_subscribers_get_no_bodies() {
+        let a = node("heads_only_a");
+        let b = node("heads_only_b");
+        let c = node("heads_only_c");
+        let config = AnnounceConfig {
+            qos: QosProfile {
+                max_head_bytes: 8,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        a.announce_with("/heads_only", "", "X", b"", &config)
+            .unwrap();
+        let config = SubscriptionConfig {
+            heads_only: true,
+            ..Default::default()
+        };
+        let heads = b.subscription_with("/heads_only", &config).unwrap();
+        let local = a.subscription_with("/heads_only", &config).unwrap();
+        let whole = c.subscription("/heads_only").unwrap();
+        for sub in [&heads, &local, &whole] {
+            first_sample(&a, "/heads_only", sub).unwrap();
+        }
+        for sub in [&heads, &local, &whole] {
+            while sub.take().is_some() {}
         }
-        let listener = maybe_listener.unwrap();
 
-        // connect to neighbors
-        let mut out_connections: Vec<UnixStream> = Default::default();
-        for i in 0..config.max_nodes {
-            let name = format!("\0inps.{:02}", i);
-            if name == self_name {
-                continue;
+        // through the head slots and, too large for them, a memfd
+        for head in [&b"head"[..], &[1; 100][..]] {
+            a.publish("/heads_only", head, b"body").unwrap();
+            for (sub, body) in [(&heads, &b""[..]), (&local, b""), (&whole, b"body")] {
+                let start = Instant::now();
+                let sample = loop {
+                    if let Some(sample) = sub.take() {
+                        break sample;
+                    }
+                    assert!(start.elapsed() < Duration::from_secs(5));
+                    std::thread::sleep(Duration::from_millis(1));
+                };
+                assert_eq!(sample.head, head);
+                assert_eq!(sample.body, body);
+            }
+        }
+    }
+
+    // what node knows of topic once it's what we're waiting for, or None
+    // after a few seconds
+    fn topic_info(
+        node: &Node,
+        topic: &str,
+        until: impl Fn(&TopicInfo) -> bool,
+    ) -> Option<TopicInfo> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            let info = node.topics().into_iter().find(|info| info.topic == topic);
+            if let Some(info) = info.filter(|info| until(info)) {
+                return Some(info);
             }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        return None;
+    }
 
-            match make_seq_socket_connection(&name) {
-                Err(err) => {
-                    println!("Could not connect to {}: {}", name, err);
-                }
-                Ok(out) => {
-                    out_connections.push(out);
+    #[test]
+    fn announcing_another_type_conflicts() {
+        let mut a = node("conflict_a");
+        let b = node("conflict_b");
+        let c = node("conflict_c");
+        a.announce("/conflict", "", "X", b"").unwrap();
+        topic_info(&b, "/conflict", |_| true).unwrap();
+        let result = b.announce("/conflict", "", "Y", b"");
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::SchemaMismatch);
+
+        // unless it's compatible, then the type is Y's once X's first
+        // publisher has gone, and the later X is the odd one out
+        let config = AnnounceConfig {
+            compatible: true,
+            ..Default::default()
+        };
+        b.announce_with("/conflict", "", "Y", b"", &config).unwrap();
+        topic_info(&c, "/conflict", |info| info.publishers.len() == 2).unwrap();
+        c.announce("/conflict", "", "X", b"").unwrap();
+        let info = topic_info(&b, "/conflict", |info| info.publishers.len() == 3).unwrap();
+        assert!(info.publishers.iter().all(|publisher| !publisher.conflict));
+
+        a.shutdown().unwrap();
+        let info = topic_info(&b, "/conflict", |info| info.publishers.len() == 2).unwrap();
+        assert_eq!(info.body_type_name, "Y");
+        let conflicts: Vec<(&str, bool)> = info
+            .publishers
+            .iter()
+            .map(|publisher| (publisher.node.as_str(), publisher.conflict))
+            .collect();
+        assert_eq!(conflicts, [("conflict_b", false), ("conflict_c", true)]);
+    }
+
+    // publishes to sub until it's full, then once more while another thread
+    // takes from it, returning what it took and how long that all took
+    fn publish_past_full(publisher: &Node, topic: &str, sub: &Subscription) -> (Sample, Duration) {
+        first_sample(publisher, topic, sub).unwrap();
+        publisher.publish(topic, b"", b"1").unwrap();
+        publisher.publish(topic, b"", b"2").unwrap();
+        let start = Instant::now();
+        let taken = std::thread::scope(|scope| {
+            let taker = scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(100));
+                return sub.take().unwrap();
+            });
+            publisher.publish(topic, b"", b"3").unwrap();
+            return taker.join().unwrap();
+        });
+        return (taken, start.elapsed());
+    }
+
+    #[test]
+    fn reliable_publish_waits_for_room() {
+        let a = node("reliable_a");
+        let b = node("reliable_b");
+        let qos = QosProfile {
+            history: History::KeepLast(2),
+            reliability: Reliability::Reliable {
+                max_blocking: Duration::from_secs(10),
+            },
+            ..Default::default()
+        };
+        let config = AnnounceConfig {
+            qos: qos,
+            ..Default::default()
+        };
+        a.announce_with("/reliable", "", "X", b"", &config).unwrap();
+        a.announce_with("/reliable_local", "", "X", b"", &config)
+            .unwrap();
+        let config = SubscriptionConfig {
+            qos: qos,
+            ..Default::default()
+        };
+
+        // woken by the take, well before max_blocking, whether it's read from
+        // the segment or handed over in the node
+        let sub = b.subscription_with("/reliable", &config).unwrap();
+        let (taken, elapsed) = publish_past_full(&a, "/reliable", &sub);
+        assert_eq!(taken.body, b"1");
+        assert!(elapsed < Duration::from_secs(5));
+        let local = a.subscription_with("/reliable_local", &config).unwrap();
+        let (taken, elapsed) = publish_past_full(&a, "/reliable_local", &local);
+        assert_eq!(taken.body, b"1");
+        assert!(elapsed < Duration::from_secs(5));
+
+        // and gives up after it when nothing is taken
+        let qos = QosProfile {
+            reliability: Reliability::Reliable {
+                max_blocking: Duration::from_millis(50),
+            },
+            ..qos
+        };
+        a.state
+            .lock()
+            .unwrap()
+            .publications
+            .get_mut("/reliable")
+            .unwrap()
+            .announcement
+            .qos = qos;
+        let result = a.publish("/reliable", b"", b"4");
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn best_effort_publish_overwrites_and_counts_the_loss() {
+        let a = node("best_effort_a");
+        let b = node("best_effort_b");
+        let config = AnnounceConfig {
+            qos: QosProfile {
+                history: History::KeepLast(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        a.announce_with("/best_effort", "", "X", b"", &config)
+            .unwrap();
+        a.announce_with("/best_effort_local", "", "X", b"", &config)
+            .unwrap();
+        let lost = Arc::new(Mutex::new(Vec::new()));
+        let reported = lost.clone();
+        let config = SubscriptionConfig {
+            on_loss: Some(Arc::new(move |n| reported.lock().unwrap().push(n))),
+            ..Default::default()
+        };
+
+        // the first of the three is gone by the time it's taken, whether it's
+        // read from the segment or handed over in the node
+        let sub = b.subscription_with("/best_effort", &config).unwrap();
+        let (taken, _) = publish_past_full(&a, "/best_effort", &sub);
+        assert_eq!(taken.body, b"2");
+        assert_eq!(sub.stats().lost(), 1);
+        assert_eq!(*lost.lock().unwrap(), [1]);
+        let local = a.subscription_with("/best_effort_local", &config).unwrap();
+        let (taken, _) = publish_past_full(&a, "/best_effort_local", &local);
+        assert_eq!(taken.body, b"2");
+        assert_eq!(local.stats().lost(), 1);
+        assert_eq!(*lost.lock().unwrap(), [1, 1]);
+    }
+
+    #[test]
+    fn incompatible_publishers_are_skipped_whenever_they_announce() {
+        let reliable = QosProfile {
+            reliability: Reliability::Reliable {
+                max_blocking: Duration::from_secs(1),
+            },
+            ..Default::default()
+        };
+        let config = SubscriptionConfig {
+            qos: reliable,
+            ..Default::default()
+        };
+        let good = node("qos_good");
+        let bad = node("qos_bad");
+        let sub = node("qos_sub");
+
+        // known when subscribing, and turning up after
+        let good_config = AnnounceConfig {
+            qos: reliable,
+            ..Default::default()
+        };
+        good.announce_with("/qos_before", "", "X", b"", &good_config)
+            .unwrap();
+        bad.announce("/qos_before", "", "X", b"").unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        let before = sub.subscription_with("/qos_before", &config).unwrap();
+        let after = sub.subscription_with("/qos_after", &config).unwrap();
+        good.announce_with("/qos_after", "", "X", b"", &good_config)
+            .unwrap();
+        bad.announce("/qos_after", "", "X", b"").unwrap();
+
+        for (topic, sub) in [("/qos_before", &before), ("/qos_after", &after)] {
+            let mut heard = 0;
+            let start = Instant::now();
+            while heard < 10 && start.elapsed() < Duration::from_secs(5) {
+                good.publish(topic, b"", b"good").unwrap();
+                bad.publish(topic, b"", b"bad").unwrap();
+                std::thread::sleep(Duration::from_millis(10));
+                while let Some(sample) = sub.take() {
+                    assert_eq!(sample.info.publisher_id, good.id());
+                    heard += 1;
                 }
             }
+            assert!(heard >= 10, "{}", topic);
         }
+    }
 
-        // construct the node infromation shared memory segment
+    #[test]
+    fn local_and_segment_samples_share_their_stamp() {
+        let a = node("stamp_a");
+        let b = node("stamp_b");
+        let config = AnnounceConfig {
+            qos: QosProfile {
+                max_segment_sample_bytes: 64,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        a.announce_with("/stamp", "", "X", b"", &config).unwrap();
+        let local = a.subscription("/stamp").unwrap();
+        let remote = b.subscription("/stamp").unwrap();
+        first_sample(&a, "/stamp", &remote).unwrap();
+        while local.take().is_some() {}
 
-        // construct our notification futex
-        let futex = Futex::new()?;
+        // through the segment and, too large for it, a memfd
+        for body in [&[0; 1][..], &[0; 1000][..]] {
+            a.publish("/stamp", b"", body).unwrap();
+            let ours = local.take().unwrap().info;
+            let start = Instant::now();
+            let theirs = loop {
+                match remote.take() {
+                    Some(sample) if sample.info.sequence == ours.sequence => break sample.info,
+                    Some(_) => {}
+                    None => std::thread::sleep(Duration::from_millis(1)),
+                }
+                assert!(start.elapsed() < Duration::from_secs(5));
+            };
+            assert_eq!(theirs, ours);
+        }
+    }
 
-        // Construct socket IO thread with
-        // - shutdown event fd so we can turn it off
-        // - receiver channel for input packets + fds
-        // - join handle so we can join when we stop
-        let shutdown = EventFd::new()?;
-        let dup_shutdown = shutdown.dup()?;
-        let (tx, rx): (Sender<Packet>, Receiver<Packet>) = mpsc::channel();
-        let socket_thread = std::thread::spawn(|| -> Result<(), SocketError> {
-            return socket_loop(listener, dup_shutdown, tx);
-        });
+    #[test]
+    fn nodes_sharing_a_context() {
+        let context = Context::new(&ContextConfig {
+            max_nodes: 16,
+            handle_signals: false,
+        })
+        .unwrap();
+        let mut a = Node::with_context(&context, "shared_a").unwrap();
+        let mut b = Node::with_context(&context, "shared_b").unwrap();
+        let mut c = Node::with_context(&context, "shared_c").unwrap();
+        a.announce("/shared", "", "X", b"").unwrap();
+        b.announce("/shared_other", "", "X", b"").unwrap();
+        let wanted = b.subscription("/shared").unwrap();
+        let other = c.subscription("/shared_other").unwrap();
 
-        return Ok(Node {
-            socket_shutdown: shutdown,
-            socket_rx: rx,
-            socket_thread_handle: socket_thread,
-            futex: futex,
-        });
+        // only the subscribed sibling hears it, straight from a's segment
+        let sample = first_sample(&a, "/shared", &wanted).unwrap();
+        assert_eq!(sample.head, b"head");
+        assert_eq!(sample.body, b"body");
+        assert_eq!(sample.info.publisher_id, a.id());
+        assert!(other.take().is_none());
+
+        // and stopping the context stops them all
+        context.shutdown().unwrap();
+        for node in [&mut a, &mut b, &mut c] {
+            assert!(node.stopped().unwrap().try_decr().unwrap().is_some());
+            node.wait_for_shutdown().unwrap();
+        }
     }
 
-    pub fn announce(
-        &self,
-        topic: &str,
-        head_type_name: &str,
-        body_type_name: &str,
-        proto_defs: &[u8],
-    ) {
+    #[test]
+    fn time_ordered_samples_merge_by_stamp() {
+        let a = node("time_ordered_a");
+        let b = node("time_ordered_b");
+        let c = node("time_ordered_c");
+        a.announce("/time_ordered", "", "X", b"").unwrap();
+        b.announce("/time_ordered", "", "X", b"").unwrap();
+        let config = SubscriptionConfig {
+            time_ordered: true,
+            ..Default::default()
+        };
+        let sub = c.subscription_with("/time_ordered", &config).unwrap();
+        let start = Instant::now();
+        let mut heard = HashSet::new();
+        while heard.len() < 2 {
+            a.publish("/time_ordered", b"", b"").unwrap();
+            b.publish("/time_ordered", b"", b"").unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+            while let Some(sample) = sub.take() {
+                heard.insert(sample.info.publisher_id);
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+        }
+        while sub.take().is_some() {}
+
+        // taking after they're all out, rather than one publisher's then the
+        // other's
+        for round in 0..10u8 {
+            a.publish("/time_ordered", b"", &[2 * round]).unwrap();
+            b.publish("/time_ordered", b"", &[2 * round + 1]).unwrap();
+        }
+        let mut samples = Vec::new();
+        while let Some(sample) = sub.take() {
+            samples.push(sample);
+        }
+        let bodies: Vec<u8> = samples.iter().map(|sample| sample.body[0]).collect();
+        assert_eq!(bodies, (0..20).collect::<Vec<u8>>());
+        assert!(samples
+            .windows(2)
+            .all(|pair| pair[0].info.monotonic_ns <= pair[1].info.monotonic_ns));
     }
 
-    pub fn publish(&self, topic: &str, head: &[u8], body: &[u8]) {}
-    pub fn subscribe(&self, topic: &str, cb: &dyn Fn(&[u8], &[u8])) {}
+    #[test]
+    fn signals_shut_down_and_say_bye() {
+        // constructed in a thread of its own, the only one that blocks the
+        // signals besides the socket thread it spawns
+        let a = std::thread::spawn(|| {
+            return Node::new(&NodeConfig {
+                name: "signalled_a".to_string(),
+                max_nodes: 16,
+                handle_signals: true,
+            })
+            .unwrap();
+        })
+        .join()
+        .unwrap();
+        let b = node("signalled_b");
+        a.announce("/signalled", "", "X", b"").unwrap();
+        topic_info(&b, "/signalled", |_| true).unwrap();
+
+        // signalfd only reads what's pending for the process or the socket
+        // thread itself
+        let thread = {
+            let handle = a.context.shared.socket_thread_handle.lock().unwrap();
+            handle.as_ref().unwrap().as_pthread_t()
+        };
+        assert_eq!(unsafe { libc::pthread_kill(thread, libc::SIGTERM) }, 0);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut a = a;
+            a.wait_for_shutdown().unwrap();
+            sender.send(a).unwrap();
+        });
+        let _a = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // still around, so b can only have dropped it when told
+        let start = Instant::now();
+        while b.topics().iter().any(|info| info.topic == "/signalled") {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
 }
@@ -0,0 +1,742 @@
+use crate::errors::{ErrorKind, SocketError};
+use crate::node::Wakeup;
+use crate::qos::{Durability, History, Liveliness, QosProfile, Reliability};
+use sendfd::SendWithFd;
+use std::os::fd::RawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+
+// Messages exchanged between nodes over their seqpacket connections, any
+// file descriptors travel alongside as SCM_RIGHTS
+#[derive(Clone, Debug, PartialEq)]
+pub enum ControlMessage {
+    // we publish topic
+    Announce {
+        topic: String,
+        head_type_name: String,
+        body_type_name: String,
+        proto_defs: Vec<u8>,
+        // who announced it and when (realtime ns), the earliest announcement
+        // of a topic fixes its type
+        node: String,
+        pid: u32,
+        // the node's Node::id(), stamped on its samples
+        id: u64,
+        announced_at: u64,
+        // published anyway if the type differs from the topic's
+        compatible: bool,
+        // boxed to keep the other messages small
+        qos: Box<QosProfile>,
+    },
+    // subscribe to topic, fds: [futex memfd] or [eventfd] to ring on publish
+    Subscribe {
+        topic: String,
+        id: u64,
+        wakeup: Wakeup,
+        // the publisher has to wait for us to read, if it's reliable
+        reliable: bool,
+        // past samples we want delivered, if the publisher kept any
+        latched: usize,
+    },
+    // reply to Subscribe with the same id, fds: [segment memfd, futex memfd
+    // to wake the publisher with when moving the cursor]
+    Segment {
+        topic: String,
+        id: u64,
+        // segment cursor claimed for a reliable subscriber
+        cursor: Option<usize>,
+        // past samples to start reading from
+        latched: usize,
+        // Oversized follows for sequences skipped from this one on, earlier
+        // ones were skipped before we subscribed
+        oversized_from: u64,
+    },
+    // A larger segment the publisher of topic now writes to instead of the
+    // one sent for subscription id, read on from once that one is drained,
+    // fds: [segment memfd]
+    SegmentOffer {
+        topic: String,
+        id: u64,
+        // the cursor claimed in the old one, carried over
+        cursor: Option<usize>,
+    },
+    // a sample of topic too large for the segment, for subscription id,
+    // fds: [sealed memfd]
+    Oversized {
+        topic: String,
+        id: u64,
+        sequence: u64,
+    },
+    // sender is shutting down
+    Bye,
+    // sender has just joined its process and wants to be announced to
+    Hello,
+}
+
+// Which node of the sending process a message is from and which node of the
+// receiving process it's for, as one connection carries messages between
+// every node of both. Goes ahead of every message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub from: u32,
+    pub to: u32,
+}
+
+// in a Route, every node of the process
+pub(crate) const EVERY_NODE: u32 = u32::MAX;
+const ROUTE_BYTES: usize = 8;
+
+// Largest encoded message, receive buffers are this size and larger messages
+// fail to send rather than arrive truncated
+pub(crate) const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+// SCM_MAX_FD, more fds than this go in packets of their own ahead of the
+// message
+pub(crate) const MAX_FDS: usize = 253;
+
+const ANNOUNCE: u8 = 1;
+const SUBSCRIBE: u8 = 2;
+const SEGMENT: u8 = 3;
+const BYE: u8 = 4;
+const OVERSIZED: u8 = 5;
+const SEGMENT_OFFER: u8 = 6;
+const HELLO: u8 = 7;
+
+const WAKEUP_FUTEX: u8 = 0;
+const WAKEUP_EVENTFD: u8 = 1;
+
+const BEST_EFFORT: u8 = 0;
+const RELIABLE: u8 = 1;
+
+const LIVELINESS_AUTOMATIC: u8 = 0;
+const LIVELINESS_MANUAL: u8 = 1;
+
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn put_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn put_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_bytes(&mut self, value: &[u8]) {
+        self.put_u64(value.len() as u64);
+        self.bytes.extend_from_slice(value);
+    }
+
+    // history depth is 0 for KeepAll, durability depth 0 for Volatile, and
+    // durations 0 for None
+    fn put_qos(&mut self, value: &QosProfile) {
+        match value.history {
+            History::KeepLast(depth) => self.put_u64(depth as u64),
+            History::KeepAll => self.put_u64(0),
+        }
+        match value.reliability {
+            Reliability::BestEffort => {
+                self.put_u8(BEST_EFFORT);
+                self.put_u64(0);
+            }
+            Reliability::Reliable { max_blocking } => {
+                self.put_u8(RELIABLE);
+                self.put_u64(max_blocking.as_nanos() as u64);
+            }
+        }
+        match value.durability {
+            Durability::Volatile => self.put_u64(0),
+            Durability::TransientLocal { depth } => self.put_u64(depth as u64),
+        }
+        self.put_u64(
+            value
+                .deadline
+                .map_or(0, |deadline| deadline.as_nanos() as u64),
+        );
+        match value.liveliness {
+            Liveliness::Automatic => self.put_u8(LIVELINESS_AUTOMATIC),
+            Liveliness::Manual => self.put_u8(LIVELINESS_MANUAL),
+        }
+        self.put_u64(
+            value
+                .lease_duration
+                .map_or(0, |lease| lease.as_nanos() as u64),
+        );
+        self.put_u64(
+            value
+                .lifespan
+                .map_or(0, |lifespan| lifespan.as_nanos() as u64),
+        );
+        self.put_u64(value.max_segment_sample_bytes as u64);
+        self.put_u64(value.segment_bytes.map_or(0, |bytes| bytes as u64));
+        self.put_u64(value.max_head_bytes as u64);
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SocketError> {
+        if n > self.bytes.len() {
+            return Err(SocketError::new(format!(
+                "Control message truncated, wanted {}B but only {}B left",
+                n,
+                self.bytes.len()
+            )));
+        }
+        let (out, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        return Ok(out);
+    }
+
+    fn get_u8(&mut self) -> Result<u8, SocketError> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn get_u64(&mut self) -> Result<u64, SocketError> {
+        return Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()));
+    }
+
+    fn get_bytes(&mut self) -> Result<Vec<u8>, SocketError> {
+        let len = self.get_u64()?;
+        return Ok(self.take(len as usize)?.to_vec());
+    }
+
+    fn get_qos(&mut self) -> Result<QosProfile, SocketError> {
+        let history = match self.get_u64()? {
+            0 => History::KeepAll,
+            depth => History::KeepLast(depth as usize),
+        };
+        let reliability = match (self.get_u8()?, self.get_u64()?) {
+            (BEST_EFFORT, _) => Reliability::BestEffort,
+            (RELIABLE, max_blocking) => Reliability::Reliable {
+                max_blocking: std::time::Duration::from_nanos(max_blocking),
+            },
+            (other, _) => {
+                return Err(SocketError::new(format!("Unknown reliability: {}", other)));
+            }
+        };
+        let durability = match self.get_u64()? {
+            0 => Durability::Volatile,
+            depth => Durability::TransientLocal {
+                depth: depth as usize,
+            },
+        };
+        let deadline = match self.get_u64()? {
+            0 => None,
+            ns => Some(std::time::Duration::from_nanos(ns)),
+        };
+        let liveliness = match self.get_u8()? {
+            LIVELINESS_AUTOMATIC => Liveliness::Automatic,
+            LIVELINESS_MANUAL => Liveliness::Manual,
+            other => {
+                return Err(SocketError::new(format!("Unknown liveliness: {}", other)));
+            }
+        };
+        let lease_duration = match self.get_u64()? {
+            0 => None,
+            ns => Some(std::time::Duration::from_nanos(ns)),
+        };
+        let lifespan = match self.get_u64()? {
+            0 => None,
+            ns => Some(std::time::Duration::from_nanos(ns)),
+        };
+        return Ok(QosProfile {
+            history: history,
+            reliability: reliability,
+            durability: durability,
+            deadline: deadline,
+            liveliness: liveliness,
+            lease_duration: lease_duration,
+            lifespan: lifespan,
+            max_segment_sample_bytes: self.get_u64()? as usize,
+            segment_bytes: match self.get_u64()? {
+                0 => None,
+                bytes => Some(bytes as usize),
+            },
+            max_head_bytes: self.get_u64()? as usize,
+        });
+    }
+
+    fn get_string(&mut self) -> Result<String, SocketError> {
+        match String::from_utf8(self.get_bytes()?) {
+            Ok(out) => {
+                return Ok(out);
+            }
+            Err(err) => {
+                return Err(SocketError::new(format!(
+                    "Control message has invalid string: {}",
+                    err
+                )));
+            }
+        }
+    }
+}
+
+impl ControlMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Encoder { bytes: Vec::new() };
+        match self {
+            ControlMessage::Announce {
+                topic,
+                head_type_name,
+                body_type_name,
+                proto_defs,
+                node,
+                pid,
+                id,
+                announced_at,
+                compatible,
+                qos,
+            } => {
+                out.put_u8(ANNOUNCE);
+                out.put_bytes(topic.as_bytes());
+                out.put_bytes(head_type_name.as_bytes());
+                out.put_bytes(body_type_name.as_bytes());
+                out.put_bytes(proto_defs);
+                out.put_bytes(node.as_bytes());
+                out.put_u64(*pid as u64);
+                out.put_u64(*id);
+                out.put_u64(*announced_at);
+                out.put_u8(*compatible as u8);
+                out.put_qos(qos);
+            }
+            ControlMessage::Subscribe {
+                topic,
+                id,
+                wakeup,
+                reliable,
+                latched,
+            } => {
+                out.put_u8(SUBSCRIBE);
+                out.put_bytes(topic.as_bytes());
+                out.put_u64(*id);
+                match wakeup {
+                    Wakeup::Futex => out.put_u8(WAKEUP_FUTEX),
+                    Wakeup::EventFd => out.put_u8(WAKEUP_EVENTFD),
+                }
+                out.put_u8(*reliable as u8);
+                out.put_u64(*latched as u64);
+            }
+            ControlMessage::Segment {
+                topic,
+                id,
+                cursor,
+                latched,
+                oversized_from,
+            } => {
+                out.put_u8(SEGMENT);
+                out.put_bytes(topic.as_bytes());
+                out.put_u64(*id);
+                // 0 for none, index + 1 otherwise
+                out.put_u64(cursor.map_or(0, |index| index as u64 + 1));
+                out.put_u64(*latched as u64);
+                out.put_u64(*oversized_from);
+            }
+            ControlMessage::SegmentOffer { topic, id, cursor } => {
+                out.put_u8(SEGMENT_OFFER);
+                out.put_bytes(topic.as_bytes());
+                out.put_u64(*id);
+                out.put_u64(cursor.map_or(0, |index| index as u64 + 1));
+            }
+            ControlMessage::Oversized {
+                topic,
+                id,
+                sequence,
+            } => {
+                out.put_u8(OVERSIZED);
+                out.put_bytes(topic.as_bytes());
+                out.put_u64(*id);
+                out.put_u64(*sequence);
+            }
+            ControlMessage::Bye => {
+                out.put_u8(BYE);
+            }
+            ControlMessage::Hello => {
+                out.put_u8(HELLO);
+            }
+        }
+        return out.bytes;
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<ControlMessage, SocketError> {
+        let mut input = Decoder { bytes: bytes };
+        match input.get_u8()? {
+            ANNOUNCE => {
+                return Ok(ControlMessage::Announce {
+                    topic: input.get_string()?,
+                    head_type_name: input.get_string()?,
+                    body_type_name: input.get_string()?,
+                    proto_defs: input.get_bytes()?,
+                    node: input.get_string()?,
+                    pid: input.get_u64()? as u32,
+                    id: input.get_u64()?,
+                    announced_at: input.get_u64()?,
+                    compatible: input.get_u8()? != 0,
+                    qos: Box::new(input.get_qos()?),
+                });
+            }
+            SUBSCRIBE => {
+                let topic = input.get_string()?;
+                let id = input.get_u64()?;
+                let wakeup = match input.get_u8()? {
+                    WAKEUP_FUTEX => Wakeup::Futex,
+                    WAKEUP_EVENTFD => Wakeup::EventFd,
+                    other => {
+                        return Err(SocketError::new(format!("Unknown wakeup: {}", other)));
+                    }
+                };
+                return Ok(ControlMessage::Subscribe {
+                    topic: topic,
+                    id: id,
+                    wakeup: wakeup,
+                    reliable: input.get_u8()? != 0,
+                    latched: input.get_u64()? as usize,
+                });
+            }
+            SEGMENT => {
+                let topic = input.get_string()?;
+                let id = input.get_u64()?;
+                let cursor = match input.get_u64()? {
+                    0 => None,
+                    index => Some(index as usize - 1),
+                };
+                return Ok(ControlMessage::Segment {
+                    topic: topic,
+                    id: id,
+                    cursor: cursor,
+                    latched: input.get_u64()? as usize,
+                    oversized_from: input.get_u64()?,
+                });
+            }
+            SEGMENT_OFFER => {
+                let topic = input.get_string()?;
+                let id = input.get_u64()?;
+                let cursor = match input.get_u64()? {
+                    0 => None,
+                    index => Some(index as usize - 1),
+                };
+                return Ok(ControlMessage::SegmentOffer {
+                    topic: topic,
+                    id: id,
+                    cursor: cursor,
+                });
+            }
+            OVERSIZED => {
+                return Ok(ControlMessage::Oversized {
+                    topic: input.get_string()?,
+                    id: input.get_u64()?,
+                    sequence: input.get_u64()?,
+                });
+            }
+            BYE => {
+                return Ok(ControlMessage::Bye);
+            }
+            HELLO => {
+                return Ok(ControlMessage::Hello);
+            }
+            other => {
+                return Err(SocketError::new(format!(
+                    "Unknown control message: {}",
+                    other
+                )));
+            }
+        }
+    }
+
+    // a packet as sent, the route followed by the message
+    pub fn decode_routed(bytes: &[u8]) -> Result<(Route, ControlMessage), SocketError> {
+        if bytes.len() < ROUTE_BYTES {
+            return Err(SocketError::new(format!(
+                "Control message of {}B is too short to be routed",
+                bytes.len()
+            )));
+        }
+        let route = Route {
+            from: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            to: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        };
+        return Ok((route, ControlMessage::decode(&bytes[ROUTE_BYTES..])?));
+    }
+
+    // Errs with ErrorKind::Protocol if the message can't be sent, before
+    // anything's been done on the strength of sending it
+    pub fn check_size(&self) -> Result<(), SocketError> {
+        return check_len(ROUTE_BYTES + self.encode().len());
+    }
+
+    pub fn send(
+        &self,
+        connection: &Connection,
+        route: Route,
+        fds: &[RawFd],
+    ) -> Result<(), SocketError> {
+        let mut bytes = Vec::with_capacity(ROUTE_BYTES);
+        bytes.extend_from_slice(&route.from.to_le_bytes());
+        bytes.extend_from_slice(&route.to.to_le_bytes());
+        bytes.extend_from_slice(&self.encode());
+        check_len(bytes.len())?;
+        // all but the last chunk of fds go ahead in packets with no bytes,
+        // the receiver holds on to them until the message itself arrives, so
+        // nobody else's may come in between
+        let _sending = connection.sending.lock().unwrap();
+        let stream = &connection.stream;
+        let split = fds.len().saturating_sub(1) / MAX_FDS * MAX_FDS;
+        for chunk in fds[..split].chunks(MAX_FDS) {
+            send_with_fd(stream, &[], chunk)?;
+        }
+        return send_with_fd(stream, &bytes, &fds[split..]);
+    }
+}
+
+// A connection to another process, which any thread may send on
+pub(crate) struct Connection {
+    stream: UnixStream,
+    // held throughout a message, which can take several packets
+    sending: Mutex<()>,
+}
+
+impl Connection {
+    pub fn new(stream: UnixStream) -> Connection {
+        return Connection {
+            stream: stream,
+            sending: Mutex::new(()),
+        };
+    }
+}
+
+fn check_len(len: usize) -> Result<(), SocketError> {
+    if len > MAX_MESSAGE_BYTES {
+        return Err(SocketError::with_kind(
+            ErrorKind::Protocol,
+            format!(
+                "Control message of {}B is larger than the maximum of {}B",
+                len, MAX_MESSAGE_BYTES
+            ),
+        ));
+    }
+    return Ok(());
+}
+
+fn send_with_fd(stream: &UnixStream, bytes: &[u8], fds: &[RawFd]) -> Result<(), SocketError> {
+    match stream.send_with_fd(bytes, fds) {
+        Ok(_) => {
+            return Ok(());
+        }
+        Err(err) => {
+            return Err(SocketError::new(format!(
+                "Failed to send control message: {}",
+                err
+            )));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epoll::{DescribedInput, Epoll, Packet};
+    use crate::event::EventFd;
+    use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd};
+    use std::time::Duration;
+
+    // one of each, with nothing left at its default
+    fn messages() -> Vec<ControlMessage> {
+        let qos = QosProfile {
+            history: History::KeepAll,
+            reliability: Reliability::Reliable {
+                max_blocking: Duration::from_millis(3),
+            },
+            durability: Durability::TransientLocal { depth: 2 },
+            deadline: Some(Duration::from_millis(5)),
+            liveliness: Liveliness::Manual,
+            lease_duration: Some(Duration::from_millis(7)),
+            lifespan: Some(Duration::from_millis(11)),
+            max_segment_sample_bytes: 13,
+            segment_bytes: Some(17),
+            max_head_bytes: 19,
+        };
+        return vec![
+            ControlMessage::Announce {
+                topic: "/topic".to_string(),
+                head_type_name: "Head".to_string(),
+                body_type_name: "pkg.Body".to_string(),
+                proto_defs: vec![1, 2, 3],
+                node: "node".to_string(),
+                pid: 23,
+                id: 27,
+                announced_at: 29,
+                compatible: true,
+                qos: Box::new(qos),
+            },
+            ControlMessage::Subscribe {
+                topic: "/topic".to_string(),
+                id: 31,
+                wakeup: Wakeup::Futex,
+                reliable: true,
+                latched: 37,
+            },
+            ControlMessage::Subscribe {
+                topic: "/topic".to_string(),
+                id: 41,
+                wakeup: Wakeup::EventFd,
+                reliable: false,
+                latched: 0,
+            },
+            ControlMessage::Segment {
+                topic: "/topic".to_string(),
+                id: 43,
+                cursor: Some(0),
+                latched: 47,
+                oversized_from: 53,
+            },
+            ControlMessage::SegmentOffer {
+                topic: "/topic".to_string(),
+                id: 59,
+                cursor: None,
+            },
+            ControlMessage::Oversized {
+                topic: "/topic".to_string(),
+                id: 61,
+                sequence: 67,
+            },
+            ControlMessage::Bye,
+            ControlMessage::Hello,
+        ];
+    }
+
+    // a connected seqpacket pair like those between nodes
+    fn connection() -> (Connection, Epoll, u64) {
+        let mut fds: [libc::c_int; 2] = [0; 2];
+        let ret =
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+        assert_eq!(ret, 0);
+        let (ours, theirs) = unsafe {
+            (
+                UnixStream::from_raw_fd(fds[0]),
+                UnixStream::from_raw_fd(fds[1]),
+            )
+        };
+        let mut epoll = Epoll::new().unwrap();
+        let key = epoll.add_stream(theirs).unwrap();
+        return (Connection::new(ours), epoll, key);
+    }
+
+    // the next packet to arrive, holding on to fds sent ahead of it
+    fn receive(epoll: &mut Epoll) -> Option<Result<Packet, SocketError>> {
+        loop {
+            match epoll.wait(Some(Duration::from_millis(100))) {
+                Ok(Some(DescribedInput::Packet(packet))) => {
+                    return Some(Ok(packet));
+                }
+                Ok(Some(DescribedInput::Pending)) => {}
+                Ok(Some(_)) => panic!("not a packet"),
+                Ok(None) => {
+                    return None;
+                }
+                Err(err) => {
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for message in messages() {
+            assert_eq!(ControlMessage::decode(&message.encode()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn routed_messages_arrive_with_their_fds() {
+        let (connection, mut epoll, _) = connection();
+        let route = Route {
+            from: 1,
+            to: EVERY_NODE,
+        };
+        // more than fit in one packet
+        let event = EventFd::new().unwrap();
+        let fds: Vec<RawFd> = (0..MAX_FDS + 2)
+            .map(|_| event.dup().unwrap().into_raw_fd())
+            .collect();
+        for message in messages() {
+            message.send(&connection, route, &fds).unwrap();
+            let packet = receive(&mut epoll).unwrap().unwrap();
+            assert_eq!(
+                ControlMessage::decode_routed(&packet.bytes).unwrap(),
+                (route, message)
+            );
+            assert_eq!(packet.fds.len(), fds.len());
+            for fd in packet.fds {
+                unsafe {
+                    libc::close(fd);
+                }
+            }
+        }
+        for fd in fds {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_frames_fail_to_decode() {
+        for message in messages() {
+            let bytes = message.encode();
+            for len in 0..bytes.len() {
+                assert!(ControlMessage::decode(&bytes[..len]).is_err());
+            }
+        }
+        assert!(ControlMessage::decode_routed(&[0; ROUTE_BYTES - 1]).is_err());
+        assert!(ControlMessage::decode(&[0]).is_err());
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let (connection, mut epoll, key) = connection();
+        let route = Route { from: 0, to: 0 };
+
+        // refused before sending
+        let message = ControlMessage::Announce {
+            topic: "/topic".to_string(),
+            head_type_name: String::new(),
+            body_type_name: String::new(),
+            proto_defs: vec![0; MAX_MESSAGE_BYTES],
+            node: String::new(),
+            pid: 0,
+            id: 0,
+            announced_at: 0,
+            compatible: false,
+            qos: Default::default(),
+        };
+        assert_eq!(
+            message.check_size().unwrap_err().kind(),
+            ErrorKind::Protocol
+        );
+        let sent = message.send(&connection, route, &[]);
+        assert_eq!(sent.unwrap_err().kind(), ErrorKind::Protocol);
+        assert!(receive(&mut epoll).is_none());
+
+        // and dropped rather than read in part when one arrives anyway
+        let bytes = vec![0u8; MAX_MESSAGE_BYTES + 1];
+        let stream = &connection.stream;
+        let ret = unsafe {
+            libc::send(
+                stream.as_raw_fd(),
+                bytes.as_ptr() as *const libc::c_void,
+                bytes.len(),
+                0,
+            )
+        };
+        assert_eq!(ret as usize, bytes.len());
+        let received = receive(&mut epoll).unwrap();
+        assert_eq!(received.err().unwrap().kind(), ErrorKind::Protocol);
+        assert!(receive(&mut epoll).is_none());
+        epoll.remove(key).unwrap();
+    }
+}
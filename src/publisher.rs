@@ -0,0 +1,96 @@
+use crate::errors::SocketError;
+#[cfg(feature = "serde")]
+use crate::node::publish;
+use crate::node::NodeState;
+#[cfg(feature = "async")]
+use crate::node::{try_publish, Blocked};
+use crate::shared_segment::Sample;
+use std::sync::{Arc, Mutex, Weak};
+
+// Publishes an announced topic. The default, Publisher<Sample>, takes raw
+// head and body bytes (async feature), other types are encoded into the body
+// with the codec chosen when advertising them (serde feature).
+pub struct Publisher<T = Sample> {
+    topic: String,
+    state: Weak<Mutex<NodeState>>,
+    // None for raw publishers
+    #[cfg(feature = "serde")]
+    encode: Option<fn(&T) -> Result<Vec<u8>, SocketError>>,
+    #[cfg(not(feature = "serde"))]
+    encode: std::marker::PhantomData<T>,
+}
+
+impl<T> Publisher<T> {
+    #[cfg(feature = "async")]
+    pub(crate) fn raw(topic: &str, state: Weak<Mutex<NodeState>>) -> Publisher<T> {
+        return Publisher {
+            topic: topic.to_string(),
+            state: state,
+            #[cfg(feature = "serde")]
+            encode: None,
+            #[cfg(not(feature = "serde"))]
+            encode: std::marker::PhantomData,
+        };
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn typed(
+        topic: &str,
+        state: Weak<Mutex<NodeState>>,
+        encode: fn(&T) -> Result<Vec<u8>, SocketError>,
+    ) -> Publisher<T> {
+        return Publisher {
+            topic: topic.to_string(),
+            state: state,
+            encode: Some(encode),
+        };
+    }
+
+    pub fn topic(&self) -> &str {
+        return &self.topic;
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn encode(&self, value: &T) -> Result<Vec<u8>, SocketError> {
+        match self.encode {
+            Some(encode) => {
+                return encode(value);
+            }
+            None => {
+                return Err(SocketError::new(format!(
+                    "Publisher for {} has no codec",
+                    self.topic
+                )));
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn publish_bytes(&self, head: &[u8], body: &[u8]) -> Result<(), SocketError> {
+        return publish(&*self.state()?, &self.topic, head, body);
+    }
+
+    // publishes unless subscribers hold it up, see try_publish()
+    #[cfg(feature = "async")]
+    pub(crate) fn try_publish_bytes(
+        &self,
+        head: &[u8],
+        body: &[u8],
+    ) -> Result<Option<Blocked>, SocketError> {
+        return try_publish(&*self.state()?, &self.topic, head, body);
+    }
+
+    fn state(&self) -> Result<Arc<Mutex<NodeState>>, SocketError> {
+        match self.state.upgrade() {
+            Some(state) => {
+                return Ok(state);
+            }
+            None => {
+                return Err(SocketError::new(format!(
+                    "Publish to {} after the node shut down",
+                    self.topic
+                )));
+            }
+        }
+    }
+}
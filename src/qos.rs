@@ -0,0 +1,295 @@
+use crate::errors::{ErrorKind, SocketError};
+use std::time::Duration;
+
+// depth of the segment when nothing else is asked for
+pub(crate) const DEFAULT_DEPTH: usize = 16;
+pub(crate) const DEFAULT_MAX_SEGMENT_SAMPLE_BYTES: usize = 64 * 1024;
+pub(crate) const DEFAULT_MAX_HEAD_BYTES: usize = 256;
+// samples a segment has room for before it first has to grow
+pub(crate) const INITIAL_SAMPLE_BYTES: usize = 4 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum History {
+    // the segment holds the last n samples
+    KeepLast(usize),
+    // the segment holds DEFAULT_DEPTH samples, and none is overwritten before
+    // every subscriber has read it, as if they all were reliable
+    KeepAll,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reliability {
+    // the oldest sample is overwritten whether or not it's been read
+    BestEffort,
+    // publish waits up to max_blocking for reliable subscribers to read the
+    // sample it would overwrite, then fails with ErrorKind::WouldBlock
+    Reliable { max_blocking: Duration },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    // subscribers only see what's published after they're matched
+    Volatile,
+    // the last depth samples still in the segment are delivered to a newly
+    // matched subscriber before anything live, for topics published once
+    TransientLocal { depth: usize },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Liveliness {
+    // the publishing node asserts it for as long as it's running
+    Automatic,
+    // only publishing or Node::assert_liveliness() does
+    Manual,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QosProfile {
+    pub history: History,
+    pub reliability: Reliability,
+    pub durability: Durability,
+    // Longest a subscriber expects to go without a sample, missed deadlines
+    // are reported to SubscriptionConfig::on_deadline_missed. A publisher
+    // offering one promises to publish at least that often, None is never.
+    pub deadline: Option<Duration>,
+    pub liveliness: Liveliness,
+    // How long a publisher is considered alive after asserting liveliness,
+    // None is forever. Changes are reported to
+    // SubscriptionConfig::on_liveliness_changed.
+    pub lease_duration: Option<Duration>,
+    // Samples older than this when read are skipped and counted as expired in
+    // SubscriptionStats. The shorter of the publisher's and the subscriber's
+    // applies.
+    pub lifespan: Option<Duration>,
+    // Largest sample, head and body together, the segment grows to hold.
+    // Larger ones are still published, but sent to each subscriber in a
+    // memfd of its own rather than through the segment, in their place
+    // among the others. Subscribers that join later never get them. Not
+    // part of matching, as subscribers take samples of any size.
+    pub max_segment_sample_bytes: usize,
+    // Shared by the bodies in the publisher's segment, which take only as
+    // much of it as they need. When it runs out the oldest are overwritten
+    // even if fewer than the history depth are kept. None starts with room
+    // for depth samples of a few KiB, and replaces the segment with a larger
+    // one whenever a sample doesn't fit, up to max_segment_sample_bytes.
+    pub segment_bytes: Option<usize>,
+    // Room for the head in each of the segment's head slots, which are kept
+    // apart from the bodies so heads can be read on their own. A sample
+    // with a larger head is sent like one over max_segment_sample_bytes.
+    pub max_head_bytes: usize,
+}
+
+impl Default for QosProfile {
+    fn default() -> QosProfile {
+        return QosProfile {
+            history: History::KeepLast(DEFAULT_DEPTH),
+            reliability: Reliability::BestEffort,
+            durability: Durability::Volatile,
+            deadline: None,
+            liveliness: Liveliness::Automatic,
+            lease_duration: None,
+            lifespan: None,
+            max_segment_sample_bytes: DEFAULT_MAX_SEGMENT_SAMPLE_BYTES,
+            segment_bytes: None,
+            max_head_bytes: DEFAULT_MAX_HEAD_BYTES,
+        };
+    }
+}
+
+impl QosProfile {
+    // number of samples in the publisher's segment
+    pub(crate) fn depth(&self) -> usize {
+        match self.history {
+            History::KeepLast(depth) => {
+                return depth;
+            }
+            History::KeepAll => {
+                return DEFAULT_DEPTH;
+            }
+        }
+    }
+
+    pub(crate) fn is_reliable(&self) -> bool {
+        return matches!(self.reliability, Reliability::Reliable { .. });
+    }
+
+    // number of past samples a new subscriber gets
+    pub(crate) fn latched(&self) -> usize {
+        match self.durability {
+            Durability::Volatile => {
+                return 0;
+            }
+            Durability::TransientLocal { depth } => {
+                return depth;
+            }
+        }
+    }
+
+    // Whether a subscriber asking for self can be matched with a publisher
+    // offering publisher, with the same rules as DDS request/offered. No
+    // deadline or lease is an infinite one, which only satisfies asking for
+    // none.
+    pub(crate) fn check_offered(&self, publisher: &QosProfile) -> Result<(), SocketError> {
+        if self.is_reliable() && !publisher.is_reliable() && publisher.history != History::KeepAll {
+            return Err(SocketError::with_kind(
+                ErrorKind::IncompatibleQos,
+                "Reliable subscription to a best effort publisher".to_string(),
+            ));
+        }
+        if self.latched() > 0 && publisher.latched() == 0 {
+            return Err(SocketError::with_kind(
+                ErrorKind::IncompatibleQos,
+                "Transient local subscription to a volatile publisher".to_string(),
+            ));
+        }
+        if longer(publisher.deadline, self.deadline) {
+            return Err(SocketError::with_kind(
+                ErrorKind::IncompatibleQos,
+                format!(
+                    "Publisher offers a deadline of {:?}, subscription asks for {:?}",
+                    publisher.deadline, self.deadline
+                ),
+            ));
+        }
+        if longer(publisher.lease_duration, self.lease_duration) {
+            return Err(SocketError::with_kind(
+                ErrorKind::IncompatibleQos,
+                format!(
+                    "Publisher offers a lease of {:?}, subscription asks for {:?}",
+                    publisher.lease_duration, self.lease_duration
+                ),
+            ));
+        }
+        if self.liveliness == Liveliness::Manual && publisher.liveliness == Liveliness::Automatic {
+            return Err(SocketError::with_kind(
+                ErrorKind::IncompatibleQos,
+                "Manual liveliness subscription to an automatic publisher".to_string(),
+            ));
+        }
+        return Ok(());
+    }
+}
+
+// whether offered is longer than requested, None being infinite
+fn longer(offered: Option<Duration>, requested: Option<Duration>) -> bool {
+    match (offered, requested) {
+        (_, None) => {
+            return false;
+        }
+        (None, Some(_)) => {
+            return true;
+        }
+        (Some(offered), Some(requested)) => {
+            return offered > requested;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHORT: Option<Duration> = Some(Duration::from_millis(10));
+    const LONG: Option<Duration> = Some(Duration::from_millis(100));
+
+    fn reliable() -> Reliability {
+        return Reliability::Reliable {
+            max_blocking: Duration::from_millis(100),
+        };
+    }
+
+    fn matches(requested: QosProfile, offered: QosProfile) -> bool {
+        return match requested.check_offered(&offered) {
+            Ok(()) => true,
+            Err(err) => {
+                assert_eq!(err.kind(), ErrorKind::IncompatibleQos);
+                false
+            }
+        };
+    }
+
+    #[test]
+    fn reliability() {
+        let best_effort = QosProfile::default();
+        let reliable = QosProfile {
+            reliability: reliable(),
+            ..Default::default()
+        };
+        let keep_all = QosProfile {
+            history: History::KeepAll,
+            ..Default::default()
+        };
+        assert!(matches(best_effort, best_effort));
+        assert!(matches(best_effort, reliable));
+        assert!(matches(reliable, reliable));
+        assert!(!matches(reliable, best_effort));
+        assert!(matches(reliable, keep_all));
+    }
+
+    #[test]
+    fn durability() {
+        let volatile = QosProfile::default();
+        let transient_local = QosProfile {
+            durability: Durability::TransientLocal { depth: 1 },
+            ..Default::default()
+        };
+        assert!(matches(volatile, volatile));
+        assert!(matches(volatile, transient_local));
+        assert!(matches(transient_local, transient_local));
+        assert!(!matches(transient_local, volatile));
+    }
+
+    #[test]
+    fn deadline() {
+        let with = |deadline: Option<Duration>| QosProfile {
+            deadline: deadline,
+            ..Default::default()
+        };
+        // requested, offered
+        assert!(matches(with(None), with(None)));
+        assert!(matches(with(None), with(LONG)));
+        assert!(matches(with(LONG), with(SHORT)));
+        assert!(matches(with(LONG), with(LONG)));
+        assert!(!matches(with(SHORT), with(LONG)));
+        assert!(!matches(with(LONG), with(None)));
+    }
+
+    #[test]
+    fn lease_duration() {
+        let with = |lease_duration: Option<Duration>| QosProfile {
+            lease_duration: lease_duration,
+            ..Default::default()
+        };
+        // requested, offered
+        assert!(matches(with(None), with(None)));
+        assert!(matches(with(None), with(LONG)));
+        assert!(matches(with(LONG), with(SHORT)));
+        assert!(matches(with(LONG), with(LONG)));
+        assert!(!matches(with(SHORT), with(LONG)));
+        assert!(!matches(with(LONG), with(None)));
+    }
+
+    #[test]
+    fn liveliness() {
+        let automatic = QosProfile::default();
+        let manual = QosProfile {
+            liveliness: Liveliness::Manual,
+            ..Default::default()
+        };
+        assert!(matches(automatic, automatic));
+        assert!(matches(automatic, manual));
+        assert!(matches(manual, manual));
+        assert!(!matches(manual, automatic));
+    }
+
+    #[test]
+    fn max_segment_sample_bytes_isnt_matched() {
+        let with = |max_segment_sample_bytes: usize| QosProfile {
+            max_segment_sample_bytes: max_segment_sample_bytes,
+            ..Default::default()
+        };
+        assert!(matches(with(1024), with(1024)));
+        assert!(matches(with(2048), with(1024)));
+        assert!(matches(with(1024), with(2048)));
+    }
+}
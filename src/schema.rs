@@ -0,0 +1,413 @@
+use crate::errors::{ErrorKind, SocketError};
+use prost::Message;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, EnumDescriptorProto, FileDescriptorSet};
+use std::collections::{HashMap, HashSet};
+
+// What a subscriber expects the body of a topic to be
+#[derive(Clone, Debug)]
+pub struct Schema {
+    // full protobuf message name, e.g. "geometry.Pose"
+    pub type_name: String,
+    // serialized FileDescriptorSet defining type_name
+    pub proto_defs: Vec<u8>,
+}
+
+// Definitions announced by every node we've heard from, and our own, keyed by
+// full message name. The first definition of a type wins.
+#[derive(Default)]
+pub(crate) struct SchemaRegistry {
+    schemas: HashMap<String, Vec<u8>>,
+}
+
+impl SchemaRegistry {
+    pub(crate) fn register(&mut self, type_names: &[&str], proto_defs: &[u8]) {
+        for type_name in type_names {
+            let type_name = type_name.trim_start_matches('.');
+            if type_name.is_empty() || self.schemas.contains_key(type_name) {
+                continue;
+            }
+            self.schemas
+                .insert(type_name.to_string(), proto_defs.to_vec());
+        }
+    }
+
+    pub(crate) fn get(&self, type_name: &str) -> Option<&Vec<u8>> {
+        return self.schemas.get(type_name.trim_start_matches('.'));
+    }
+}
+
+fn mismatch(descr: String) -> SocketError {
+    return SocketError::with_kind(ErrorKind::SchemaMismatch, descr);
+}
+
+pub(crate) fn parse(proto_defs: &[u8]) -> Result<FileDescriptorSet, SocketError> {
+    match FileDescriptorSet::decode(proto_defs) {
+        Ok(set) => {
+            return Ok(set);
+        }
+        Err(err) => {
+            return Err(SocketError::new(format!(
+                "Invalid FileDescriptorSet: {}",
+                err
+            )));
+        }
+    }
+}
+
+// looks up a message by its full name, nested messages included
+pub(crate) fn find_message<'a>(
+    set: &'a FileDescriptorSet,
+    type_name: &str,
+) -> Option<&'a DescriptorProto> {
+    let type_name = type_name.trim_start_matches('.');
+    for file in set.file.iter() {
+        let mut prefix = file.package().to_string();
+        if !prefix.is_empty() {
+            prefix.push('.');
+        }
+        if let Some(found) = find_nested(&file.message_type, &prefix, type_name) {
+            return Some(found);
+        }
+    }
+    return None;
+}
+
+fn find_nested<'a>(
+    messages: &'a [DescriptorProto],
+    prefix: &str,
+    type_name: &str,
+) -> Option<&'a DescriptorProto> {
+    for message in messages.iter() {
+        let full_name = format!("{}{}", prefix, message.name());
+        if full_name == type_name {
+            return Some(message);
+        }
+        if type_name.starts_with(&full_name) {
+            let prefix = format!("{}.", full_name);
+            if let Some(found) = find_nested(&message.nested_type, &prefix, type_name) {
+                return Some(found);
+            }
+        }
+    }
+    return None;
+}
+
+// looks up an enum by its full name, whether top level or nested in a message
+pub(crate) fn find_enum<'a>(
+    set: &'a FileDescriptorSet,
+    type_name: &str,
+) -> Option<&'a EnumDescriptorProto> {
+    let type_name = type_name.trim_start_matches('.');
+    for file in set.file.iter() {
+        let mut prefix = file.package().to_string();
+        if !prefix.is_empty() {
+            prefix.push('.');
+        }
+        if let Some(found) =
+            find_nested_enum(&file.enum_type, &file.message_type, &prefix, type_name)
+        {
+            return Some(found);
+        }
+    }
+    return None;
+}
+
+fn find_nested_enum<'a>(
+    enums: &'a [EnumDescriptorProto],
+    messages: &'a [DescriptorProto],
+    prefix: &str,
+    type_name: &str,
+) -> Option<&'a EnumDescriptorProto> {
+    for found in enums.iter() {
+        if format!("{}{}", prefix, found.name()) == type_name {
+            return Some(found);
+        }
+    }
+    for message in messages.iter() {
+        let full_name = format!("{}{}", prefix, message.name());
+        if type_name.starts_with(&full_name) {
+            let prefix = format!("{}.", full_name);
+            if let Some(found) =
+                find_nested_enum(&message.enum_type, &message.nested_type, &prefix, type_name)
+            {
+                return Some(found);
+            }
+        }
+    }
+    return None;
+}
+
+// types that can be read as each other off the wire, see "Updating A Message
+// Type" in the protobuf language guide
+fn wire_compatible(expected: Type, actual: Type) -> bool {
+    const GROUPS: [&[Type]; 5] = [
+        &[
+            Type::Int32,
+            Type::Int64,
+            Type::Uint32,
+            Type::Uint64,
+            Type::Bool,
+            Type::Enum,
+        ],
+        &[Type::Sint32, Type::Sint64],
+        &[Type::Fixed32, Type::Sfixed32],
+        &[Type::Fixed64, Type::Sfixed64],
+        &[Type::String, Type::Bytes],
+    ];
+    if expected == actual {
+        return true;
+    }
+    return GROUPS
+        .iter()
+        .any(|group| group.contains(&expected) && group.contains(&actual));
+}
+
+// Checks that actual_name as published can be decoded by a subscriber built
+// against expected_name. Fields only one side knows about are fine, fields
+// both have under the same number have to agree on wire type and repetition.
+pub(crate) fn check_compatible(
+    expected: &FileDescriptorSet,
+    expected_name: &str,
+    actual: &FileDescriptorSet,
+    actual_name: &str,
+) -> Result<(), SocketError> {
+    let mut checked: HashSet<(String, String)> = HashSet::new();
+    return check_message(expected, expected_name, actual, actual_name, &mut checked);
+}
+
+// nested messages are matched by field number, so they may have been renamed
+fn check_message(
+    expected: &FileDescriptorSet,
+    expected_name: &str,
+    actual: &FileDescriptorSet,
+    actual_name: &str,
+    checked: &mut HashSet<(String, String)>,
+) -> Result<(), SocketError> {
+    let expected_name = expected_name.trim_start_matches('.');
+    let actual_name = actual_name.trim_start_matches('.');
+    if !checked.insert((expected_name.to_string(), actual_name.to_string())) {
+        return Ok(());
+    }
+
+    let expected_message = match find_message(expected, expected_name) {
+        Some(message) => message,
+        None => {
+            return Err(mismatch(format!(
+                "Expected definitions don't contain {}",
+                expected_name
+            )));
+        }
+    };
+    let actual_message = match find_message(actual, actual_name) {
+        Some(message) => message,
+        None => {
+            return Err(mismatch(format!(
+                "Published definitions don't contain {}",
+                actual_name
+            )));
+        }
+    };
+
+    for field in expected_message.field.iter() {
+        let other = match actual_message
+            .field
+            .iter()
+            .find(|other| other.number() == field.number())
+        {
+            Some(other) => other,
+            None => {
+                continue;
+            }
+        };
+
+        if !wire_compatible(field.r#type(), other.r#type())
+            || (field.label() == Label::Repeated) != (other.label() == Label::Repeated)
+        {
+            return Err(mismatch(format!(
+                "{} field {} is published as {} {:?} {:?}, expected {} {:?} {:?}",
+                expected_name,
+                field.number(),
+                other.name(),
+                other.label(),
+                other.r#type(),
+                field.name(),
+                field.label(),
+                field.r#type()
+            )));
+        }
+
+        match field.r#type() {
+            Type::Message | Type::Group => {
+                check_message(
+                    expected,
+                    field.type_name(),
+                    actual,
+                    other.type_name(),
+                    checked,
+                )?;
+            }
+            _ => {}
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost_types::{FieldDescriptorProto, FileDescriptorProto};
+
+    fn field(name: &str, number: i32, r#type: Type, label: Label) -> FieldDescriptorProto {
+        return FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            r#type: Some(r#type as i32),
+            label: Some(label as i32),
+            ..Default::default()
+        };
+    }
+
+    fn message_field(name: &str, number: i32, type_name: &str) -> FieldDescriptorProto {
+        return FieldDescriptorProto {
+            type_name: Some(type_name.to_string()),
+            ..field(name, number, Type::Message, Label::Optional)
+        };
+    }
+
+    fn message(name: &str, fields: Vec<FieldDescriptorProto>) -> DescriptorProto {
+        return DescriptorProto {
+            name: Some(name.to_string()),
+            field: fields,
+            ..Default::default()
+        };
+    }
+
+    fn set(messages: Vec<DescriptorProto>) -> FileDescriptorSet {
+        return FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                package: Some("pkg".to_string()),
+                message_type: messages,
+                ..Default::default()
+            }],
+        };
+    }
+
+    // pkg.Scalars, whose field 1 is of type and label
+    fn scalars(r#type: Type, label: Label) -> FileDescriptorSet {
+        return set(vec![message(
+            "Scalars",
+            vec![
+                field("a", 1, r#type, label),
+                field("b", 2, Type::Double, Label::Optional),
+            ],
+        )]);
+    }
+
+    fn check(expected: &FileDescriptorSet, actual: &FileDescriptorSet) -> bool {
+        return match check_compatible(expected, "pkg.Scalars", actual, ".pkg.Scalars") {
+            Ok(()) => true,
+            Err(err) => {
+                assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
+                false
+            }
+        };
+    }
+
+    #[test]
+    fn compatible_scalars() {
+        let optional = Label::Optional;
+        let pairs = [
+            (Type::Int32, Type::Int32),
+            (Type::Int32, Type::Int64),
+            (Type::Uint64, Type::Bool),
+            (Type::Enum, Type::Int32),
+            (Type::Sint32, Type::Sint64),
+            (Type::Fixed32, Type::Sfixed32),
+            (Type::Fixed64, Type::Sfixed64),
+            (Type::String, Type::Bytes),
+        ];
+        for (expected, actual) in pairs {
+            let (expected, actual) = (scalars(expected, optional), scalars(actual, optional));
+            assert!(check(&expected, &actual) && check(&actual, &expected));
+        }
+        let repeated = scalars(Type::Int32, Label::Repeated);
+        assert!(check(&repeated, &scalars(Type::Int64, Label::Repeated)));
+    }
+
+    #[test]
+    fn incompatible_scalars() {
+        let optional = Label::Optional;
+        let pairs = [
+            (Type::Int32, Type::Sint32),
+            (Type::Int32, Type::Fixed32),
+            (Type::Fixed32, Type::Fixed64),
+            (Type::Double, Type::Float),
+            (Type::String, Type::Int32),
+            (Type::Bytes, Type::Message),
+        ];
+        for (expected, actual) in pairs {
+            let (expected, actual) = (scalars(expected, optional), scalars(actual, optional));
+            assert!(!check(&expected, &actual) && !check(&actual, &expected));
+        }
+        let repeated = scalars(Type::Int32, Label::Repeated);
+        assert!(!check(&repeated, &scalars(Type::Int32, optional)));
+        assert!(!check(&scalars(Type::Int32, optional), &repeated));
+    }
+
+    #[test]
+    fn fields_only_one_side_has_are_fine() {
+        let fewer = set(vec![message(
+            "Scalars",
+            vec![field("a", 1, Type::Int32, Label::Optional)],
+        )]);
+        let more = set(vec![message(
+            "Scalars",
+            vec![
+                field("renamed", 1, Type::Int32, Label::Optional),
+                field("c", 3, Type::String, Label::Repeated),
+            ],
+        )]);
+        assert!(check(&fewer, &more));
+        assert!(check(&more, &fewer));
+    }
+
+    #[test]
+    fn nested_messages_are_matched_by_field_number() {
+        let expected = set(vec![
+            message("Scalars", vec![message_field("inner", 1, ".pkg.Inner")]),
+            message("Inner", vec![field("x", 1, Type::Int32, Label::Optional)]),
+        ]);
+        let renamed = set(vec![
+            message("Scalars", vec![message_field("inner", 1, ".pkg.Other")]),
+            message("Other", vec![field("y", 1, Type::Int64, Label::Optional)]),
+        ]);
+        let changed = set(vec![
+            message("Scalars", vec![message_field("inner", 1, ".pkg.Inner")]),
+            message("Inner", vec![field("x", 1, Type::String, Label::Optional)]),
+        ]);
+        let missing = set(vec![message(
+            "Scalars",
+            vec![message_field("inner", 1, ".pkg.Inner")],
+        )]);
+        assert!(check(&expected, &renamed));
+        assert!(!check(&expected, &changed));
+        assert!(!check(&expected, &missing));
+        assert!(!check(&missing, &expected));
+    }
+
+    #[test]
+    fn recursive_messages_are_checked_once() {
+        let tree = set(vec![message(
+            "Scalars",
+            vec![
+                field("value", 1, Type::Int32, Label::Optional),
+                FieldDescriptorProto {
+                    label: Some(Label::Repeated as i32),
+                    ..message_field("children", 2, ".pkg.Scalars")
+                },
+            ],
+        )]);
+        assert!(check(&tree, &tree));
+    }
+}
@@ -1,19 +1,140 @@
 use crate::errors::SocketError;
+use crate::futex::Futex;
 use rand::prelude::*;
-use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::sync::atomic::{fence, AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub(crate) const CRC64: crc::Crc<u64> = crc::Crc::<u64>::new(&crc::CRC_64_ECMA_182);
+
+// u64s at the top of the header, see headsize()
+const TOP_WORDS: usize = 10;
+
+// readers that can hold the writer back, see claim_cursor()
+pub(crate) const MAX_CURSORS: usize = 32;
+
+// cursor of a reader that's gone, which holds nothing back
+const CURSOR_DETACHED: u64 = u64::MAX;
+
+// each head slot starts with u64 head length, u64 body length, u64 monotonic
+// and u64 realtime publish time in ns
+const SLOT_PREFIX: usize = 32;
+
+// offset of a message sent outside the segment, see skip()
+const SKIPPED: u64 = u64::MAX;
+
+// a message's body in the data region, which the writer hands out like a
+// ring buffer
+struct Allocation {
+    seq: u64,
+    start: u64,
+    end: u64,
+}
+
+// How a segment's memory is set up, trading memory and time spent creating
+// it for no page faults once samples flow
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryOptions {
+    // Back the segment with huge pages (MFD_HUGETLB), rounded up to a whole
+    // number of them, falling back to normal pages when none are available.
+    // Up to the publisher, subscribers map whatever they're sent.
+    pub huge_pages: bool,
+    // fault the whole mapping in when it's made (MAP_POPULATE) rather than
+    // on first touch
+    pub prefault: bool,
+    // Keep the mapping resident (mlock). Failing that, usually for lack of
+    // RLIMIT_MEMLOCK, is logged and otherwise ignored.
+    pub lock: bool,
+}
 
 pub struct SharedSegmentWriter {
     num_messages: u64,
     max_message_bytes: u64,
+    // room for the head in each head slot
+    max_head_bytes: u64,
+    // size of the data region following the header
+    data_bytes: u64,
     raw_fd: RawFd,
     next_seq: u64,
+    mapping: Mapping,
+    // where the next message goes in the data region
+    data_head: u64,
+    // messages still in the data region, oldest first, which is also the
+    // order they follow data_head in
+    allocations: VecDeque<Allocation>,
+    // what grow() sets its replacement up with too
+    memory: MemoryOptions,
 }
 
 pub struct SharedSegmentReader {
     num_messages: u64,
     max_message_bytes: u64,
+    max_head_bytes: u64,
     raw_fd: RawFd,
     next_seq: u64,
+    mapping: Mapping,
+    stats: SubscriptionStats,
+    // claimed for us by the writer, which we keep up to date with next_seq
+    cursor: Option<usize>,
+    // the writer waits on it for us to move the cursor, see set_room()
+    room: Option<Arc<Futex>>,
+    // messages older than this are skipped
+    lifespan: Option<Duration>,
+    // bodies are left where they are and read as empty
+    heads_only: bool,
+}
+
+// What has become of each sequence a reader expected
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SubscriptionStats {
+    pub received: u64,
+    // overwritten before we got to them
+    pub dropped: u64,
+    pub crc_failed: u64,
+    // overwritten while we were copying them out
+    pub torn: u64,
+    // older than the lifespan by the time we got to them, skipped but not
+    // lost
+    pub expired: u64,
+}
+
+impl SubscriptionStats {
+    // samples that were published but never made it to us
+    pub fn lost(&self) -> u64 {
+        return self.dropped + self.crc_failed + self.torn;
+    }
+
+    pub(crate) fn add(&mut self, other: &SubscriptionStats) {
+        self.received += other.received;
+        self.dropped += other.dropped;
+        self.crc_failed += other.crc_failed;
+        self.torn += other.torn;
+        self.expired += other.expired;
+    }
+}
+
+// Stamped by inps on every sample, apart from the user's head
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SampleInfo {
+    // per publisher, starting from 1, so gaps are lost samples
+    pub sequence: u64,
+    // CLOCK_MONOTONIC and CLOCK_REALTIME when published
+    pub monotonic_ns: u64,
+    pub realtime_ns: u64,
+    // random per node, see Node::id()
+    pub publisher_id: u64,
+    // hash of the topic name, the same on every node
+    pub topic_id: u64,
+}
+
+#[derive(Clone)]
+pub struct Sample {
+    pub head: Vec<u8>,
+    pub body: Vec<u8>,
+    pub info: SampleInfo,
 }
 
 #[repr(C)]
@@ -21,19 +142,156 @@ struct MessageMeta {
     // negative sequences are in flight, not to be touched
     // zeros are unoccupied
     // positive sequences are previously written
-    seq: i64,
-    crc: u64,
-    offset: u64,
+    seq: AtomicI64,
+    // of the head slot, prefix included, and of the body
+    head_crc: AtomicU64,
+    crc: AtomicU64,
+    // where the body is in the mapping and how long
+    offset: AtomicU64,
+    length: AtomicU64,
+    // CLOCK_MONOTONIC ns when written, so expiry is checked without copying
+    stamp: AtomicU64,
 }
 
-fn headsize(num_messages: usize) -> usize {
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// the header is only touched atomically, slots are guarded by the sequence
+unsafe impl Send for Mapping {}
+
+fn headsize(num_messages: usize, max_head_bytes: usize) -> usize {
     // top:
     // u64 segment ID
+    // u64 number of messages
+    // u64 max message bytes
+    // u64 publisher ID
+    // u64 topic ID
+    // u64 CLOCK_MONOTONIC ns of the latest write
+    // u64 CLOCK_MONOTONIC ns liveliness was last asserted without writing
+    // u64 highest seq whose bytes may have been overwritten
+    // u64 data region bytes
+    // u64 max head bytes
+    // cursors:
+    // u64 next seq of a reader the writer has to wait on, 0 when unused
     // each message:
     // u64 seq
+    // u64 head crc
     // u64 crc
     // u64 offset
-    return 8 + 3 * 8 * num_messages;
+    // u64 length
+    // u64 stamp
+    // each message's head slot:
+    // SLOT_PREFIX
+    // head, of up to max head bytes
+    // and the data region with the bodies follows
+    return TOP_WORDS * 8
+        + MAX_CURSORS * 8
+        + (std::mem::size_of::<MessageMeta>() + headslot(max_head_bytes)) * num_messages;
+}
+
+// where message index's head slot is in the mapping
+fn head_offset(num_messages: usize, max_head_bytes: usize, index: usize) -> usize {
+    return headsize(0, 0)
+        + std::mem::size_of::<MessageMeta>() * num_messages
+        + headslot(max_head_bytes) * index;
+}
+
+pub(crate) fn clock_ns(clock: libc::clockid_t) -> u64 {
+    unsafe {
+        let mut now: libc::timespec = std::mem::zeroed();
+        libc::clock_gettime(clock, &mut now);
+        return now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64;
+    }
+}
+
+// what a body takes in the data region, which is kept 8 byte aligned
+pub(crate) fn slotsize(body_bytes: usize) -> usize {
+    return (body_bytes + 7) & !7;
+}
+
+fn headslot(max_head_bytes: usize) -> usize {
+    return (SLOT_PREFIX + max_head_bytes + 7) & !7;
+}
+
+impl Mapping {
+    fn new(
+        fd: RawFd,
+        len: usize,
+        writable: bool,
+        memory: &MemoryOptions,
+    ) -> Result<Mapping, SocketError> {
+        let mut prot = libc::PROT_READ;
+        if writable {
+            prot |= libc::PROT_WRITE;
+        }
+        let mut flags = libc::MAP_SHARED;
+        if memory.prefault {
+            flags |= libc::MAP_POPULATE;
+        }
+        unsafe {
+            let ptr = libc::mmap(std::ptr::null_mut(), len, prot, flags, fd, 0);
+            if ptr == libc::MAP_FAILED {
+                return Err(SocketError::new(format!(
+                    "Failed to map segment: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+            if memory.lock && libc::mlock(ptr, len) == -1 {
+                println!(
+                    "Failed to lock segment in memory: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+            return Ok(Mapping {
+                ptr: ptr as *mut u8,
+                len: len,
+            });
+        }
+    }
+
+    fn header(&self, index: usize) -> u64 {
+        unsafe {
+            return std::ptr::read_volatile((self.ptr as *const u64).add(index));
+        }
+    }
+
+    fn top(&self, index: usize) -> &AtomicU64 {
+        unsafe {
+            return &*(self.ptr as *const AtomicU64).add(index);
+        }
+    }
+
+    fn cursor(&self, index: usize) -> &AtomicU64 {
+        unsafe {
+            return &*(self.ptr as *const AtomicU64).add(TOP_WORDS + index);
+        }
+    }
+
+    fn meta(&self, index: usize) -> &MessageMeta {
+        unsafe {
+            return &*(self.ptr.add(headsize(0, 0)) as *const MessageMeta).add(index);
+        }
+    }
+
+    // highest sequence present in the segment, in flight or not
+    fn latest_seq(&self, num_messages: usize) -> u64 {
+        let mut latest: u64 = 0;
+        for i in 0..num_messages {
+            let seq = self.meta(i).seq.load(Ordering::Acquire).unsigned_abs();
+            latest = latest.max(seq);
+        }
+        return latest;
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
 }
 
 impl SharedSegmentWriter {
@@ -41,62 +299,1073 @@ impl SharedSegmentWriter {
     //pub fn allocate(n_bytes: usize) -> {
     //}
 
-    pub fn write(&mut self, arr: &[u8]) {
-        // choose the lowest sequence to overwrite
+    // Finds room for size bytes right after the latest message, wrapping
+    // around to the start of the data region when there's not enough left
+    // before the end. Returns where it goes and how many of the oldest
+    // messages it overwrites.
+    fn allocate(&self, size: u64) -> (u64, usize) {
+        let head = self.data_head;
+        let wraps = head + size > self.data_bytes;
+        let start = if wraps { 0 } else { head };
 
-        //
+        // the bytes between head and the end are given up when wrapping
+        let overlaps = |allocation: &Allocation| {
+            if wraps {
+                return allocation.end > head || allocation.start < size;
+            }
+            return allocation.end > head && allocation.start < head + size;
+        };
+        // empty bodies take no room, they go along with whatever's
+        // overwritten after them
+        let mut overwritten = 0;
+        for (i, allocation) in self.allocations.iter().enumerate() {
+            if allocation.start == allocation.end {
+                continue;
+            }
+            if !overlaps(allocation) {
+                break;
+            }
+            overwritten = i + 1;
+        }
+        return (start, overwritten);
+    }
+
+    // highest sequence writing a body of len bytes would overwrite, 0 for
+    // none
+    fn overwrites(&self, len: usize) -> u64 {
+        let mut overwritten: u64 = 0;
+        // slots are reused in sequence order, so the next slot always holds
+        // the lowest sequence
+        if self.next_seq > self.num_messages {
+            overwritten = self.next_seq - self.num_messages;
+        }
+        let (_, count) = self.allocate(slotsize(len) as u64);
+        if count > 0 {
+            overwritten = overwritten.max(self.allocations[count - 1].seq);
+        }
+        return overwritten;
     }
 
-    pub fn new(num_messages: usize, max_bytes: usize) -> Result<SharedSegmentWriter, SocketError> {
+    // Writes the message after the latest one, stamped as published at
+    // monotonic_ns and realtime_ns, overwriting as many of the oldest as it
+    // takes to make room. Returns its sequence.
+    pub fn write(
+        &mut self,
+        head: &[u8],
+        body: &[u8],
+        monotonic_ns: u64,
+        realtime_ns: u64,
+    ) -> Result<u64, SocketError> {
+        let len = head.len() + body.len();
+        if len as u64 > self.max_message_bytes || head.len() as u64 > self.max_head_bytes {
+            return Err(SocketError::new(format!(
+                "Message of {}B with a {}B head exceeds segment limits of {}B and {}B",
+                len,
+                head.len(),
+                self.max_message_bytes,
+                self.max_head_bytes
+            )));
+        }
+
+        let seq = self.next_seq;
+        let (index, offset) = self.reserve(body.len());
+        let head_slot: &mut [u8];
+        let body_slot: &mut [u8];
         unsafe {
-            // TODO(micah) if we want to support GPU memory we should split head
-            // and body so head can travel over the CPU
+            head_slot = std::slice::from_raw_parts_mut(
+                self.mapping.ptr.add(self.head_offset(index)),
+                SLOT_PREFIX + head.len(),
+            );
+            body_slot = std::slice::from_raw_parts_mut(self.mapping.ptr.add(offset), body.len());
+        }
+        head_slot[0..8].copy_from_slice(&(head.len() as u64).to_le_bytes());
+        head_slot[8..16].copy_from_slice(&(body.len() as u64).to_le_bytes());
+        head_slot[16..24].copy_from_slice(&monotonic_ns.to_le_bytes());
+        head_slot[24..32].copy_from_slice(&realtime_ns.to_le_bytes());
+        head_slot[SLOT_PREFIX..].copy_from_slice(head);
+        body_slot.copy_from_slice(body);
+        let head_crc = CRC64.checksum(head_slot);
+        let crc = CRC64.checksum(body_slot);
+        self.commit(index, offset, body.len(), head_crc, crc, monotonic_ns);
+        return Ok(seq);
+    }
 
-            let fd = libc::memfd_create("segment\0".as_ptr() as *const i8, 0);
-            if fd == -1 {
+    fn head_offset(&self, index: usize) -> usize {
+        return head_offset(
+            self.num_messages as usize,
+            self.max_head_bytes as usize,
+            index,
+        );
+    }
+
+    // Makes room for a body of len bytes with the next sequence and marks it
+    // in flight. Returns its meta index and the body's offset in the mapping.
+    fn reserve(&mut self, len: usize) -> (usize, usize) {
+        let seq = self.next_seq;
+        let index = ((seq - 1) % self.num_messages) as usize;
+        let size = slotsize(len) as u64;
+        let (start, count) = self.allocate(size);
+        let overwritten = self.overwrites(len);
+
+        // let readers know before touching the bytes of anything we overwrite
+        self.retire(count, overwritten);
+
+        // mark in flight before touching the data
+        self.mapping
+            .meta(index)
+            .seq
+            .swap(-(seq as i64), Ordering::Acquire);
+        fence(Ordering::Release);
+
+        self.allocations.push_back(Allocation {
+            seq: seq,
+            start: start,
+            end: start + size,
+        });
+        self.data_head = start + size;
+        let data_start = headsize(self.num_messages as usize, self.max_head_bytes as usize);
+        return (index, data_start + start as usize);
+    }
+
+    // publishes what reserve() made room for once its slots are filled in
+    fn commit(
+        &mut self,
+        index: usize,
+        offset: usize,
+        len: usize,
+        head_crc: u64,
+        crc: u64,
+        stamp: u64,
+    ) {
+        let seq = self.next_seq;
+        let meta = self.mapping.meta(index);
+        meta.head_crc.store(head_crc, Ordering::Relaxed);
+        meta.crc.store(crc, Ordering::Relaxed);
+        meta.offset.store(offset as u64, Ordering::Relaxed);
+        meta.length.store(len as u64, Ordering::Relaxed);
+        meta.stamp.store(stamp, Ordering::Relaxed);
+        meta.seq.store(seq as i64, Ordering::Release);
+        self.mapping.top(5).store(stamp, Ordering::Release);
+        self.next_seq += 1;
+    }
+
+    // Forgets the count oldest allocations, and whatever else is up to
+    // overwritten, letting readers know they're gone
+    fn retire(&mut self, count: usize, overwritten: u64) {
+        self.allocations.drain(0..count);
+        if let Some(oldest) = self.allocations.front() {
+            if oldest.seq <= overwritten {
+                // its meta is about to be reused, so it's gone either way
+                self.allocations.pop_front();
+            }
+        }
+        if overwritten > 0 {
+            self.mapping
+                .top(7)
+                .fetch_max(overwritten, Ordering::Relaxed);
+        }
+    }
+
+    // sequence the next message will be written with
+    pub fn next_sequence(&self) -> u64 {
+        return self.next_seq;
+    }
+
+    // Takes the next sequence for a message sent outside the segment, see
+    // write_sealed(), stamped as published at now. Readers stop at it until
+    // they've been handed it.
+    pub fn skip(&mut self, now: u64) -> u64 {
+        let seq = self.next_seq;
+        let index = ((seq - 1) % self.num_messages) as usize;
+        self.retire(0, seq.saturating_sub(self.num_messages));
+
+        let meta = self.mapping.meta(index);
+        meta.seq.swap(-(seq as i64), Ordering::Acquire);
+        fence(Ordering::Release);
+        meta.head_crc.store(0, Ordering::Relaxed);
+        meta.crc.store(0, Ordering::Relaxed);
+        meta.offset.store(SKIPPED, Ordering::Relaxed);
+        meta.length.store(0, Ordering::Relaxed);
+        meta.stamp.store(now, Ordering::Relaxed);
+        meta.seq.store(seq as i64, Ordering::Release);
+        self.mapping.top(5).store(now, Ordering::Release);
+
+        self.next_seq += 1;
+        return seq;
+    }
+
+    // A segment with room for num_messages messages of up to max_bytes in at
+    // least data_bytes, and no less than this one has, holding everything
+    // still in this one under the same sequences, and the same cursors.
+    // Readers move over to it with SharedSegmentReader::resume() once they've
+    // read what's here, as nothing more is written to this one.
+    pub fn grow(
+        &self,
+        num_messages: usize,
+        data_bytes: usize,
+        max_bytes: usize,
+    ) -> Result<SharedSegmentWriter, SocketError> {
+        if (max_bytes as u64) < self.max_message_bytes || (num_messages as u64) < self.num_messages
+        {
+            return Err(SocketError::new(format!(
+                "Segment of {} messages up to {}B can't replace one of {} up to {}B",
+                num_messages, max_bytes, self.num_messages, self.max_message_bytes
+            )));
+        }
+        // this one may have been rounded up to huge pages
+        let data_bytes = data_bytes.max(self.data_bytes as usize);
+        let mut grown = SharedSegmentWriter::new(
+            num_messages,
+            data_bytes,
+            max_bytes,
+            self.max_head_bytes as usize,
+            &self.memory,
+        )?;
+        for index in [3, 4, 6] {
+            let value = self.mapping.top(index).load(Ordering::Acquire);
+            grown.mapping.top(index).store(value, Ordering::Release);
+        }
+        for index in 0..MAX_CURSORS {
+            let next = self.mapping.cursor(index).load(Ordering::Acquire);
+            grown.mapping.cursor(index).store(next, Ordering::Release);
+        }
+
+        // we're the only writer, so what's here stays put while we copy
+        let reclaimed = self.mapping.top(7).load(Ordering::Acquire);
+        let first = self.next_seq.saturating_sub(self.num_messages).max(1);
+        for seq in first..self.next_seq {
+            let index = ((seq - 1) % self.num_messages) as usize;
+            let meta = self.mapping.meta(index);
+            let offset = meta.offset.load(Ordering::Relaxed);
+            let length = meta.length.load(Ordering::Relaxed) as usize;
+            let stamp = meta.stamp.load(Ordering::Relaxed);
+            if meta.seq.load(Ordering::Acquire) != seq as i64 {
+                continue;
+            }
+            grown.next_seq = seq;
+            if offset == SKIPPED {
+                grown.skip(stamp);
+            } else if seq > reclaimed {
+                let (grown_index, grown_offset) = grown.reserve(length);
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        self.mapping.ptr.add(self.head_offset(index)),
+                        grown.mapping.ptr.add(grown.head_offset(grown_index)),
+                        headslot(self.max_head_bytes as usize),
+                    );
+                    std::ptr::copy_nonoverlapping(
+                        self.mapping.ptr.add(offset as usize),
+                        grown.mapping.ptr.add(grown_offset),
+                        length,
+                    );
+                }
+                grown.commit(
+                    grown_index,
+                    grown_offset,
+                    length,
+                    meta.head_crc.load(Ordering::Relaxed),
+                    meta.crc.load(Ordering::Relaxed),
+                    stamp,
+                );
+            }
+        }
+        grown.next_seq = self.next_seq;
+        grown.mapping.top(7).fetch_max(reclaimed, Ordering::Relaxed);
+        let written = self.mapping.top(5).load(Ordering::Acquire);
+        grown.mapping.top(5).store(written, Ordering::Release);
+        return Ok(grown);
+    }
+
+    pub fn max_message_bytes(&self) -> usize {
+        return self.max_message_bytes as usize;
+    }
+
+    pub fn max_head_bytes(&self) -> usize {
+        return self.max_head_bytes as usize;
+    }
+
+    // Stamped into the header for readers to put in SampleInfo. Set before
+    // sharing the segment.
+    pub fn set_origin(&mut self, publisher_id: u64, topic_id: u64) {
+        unsafe {
+            let top = self.mapping.ptr as *mut u64;
+            std::ptr::write_volatile(top.add(3), publisher_id);
+            std::ptr::write_volatile(top.add(4), topic_id);
+        }
+    }
+
+    // Reserves a cursor for a reader that has to see every message, nothing
+    // it hasn't read gets overwritten (see is_full()). The reader starts with
+    // the last latched messages. None when all are taken.
+    pub fn claim_cursor(&mut self, latched: usize) -> Option<usize> {
+        let latched = latched.min(self.num_messages as usize) as u64;
+        let oldest = self
+            .allocations
+            .front()
+            .map_or(self.next_seq, |allocation| allocation.seq);
+        let start = self.next_seq.saturating_sub(latched).max(oldest).max(1);
+        for index in 0..MAX_CURSORS {
+            let cursor = self.mapping.cursor(index);
+            if cursor.load(Ordering::Acquire) == 0 {
+                cursor.store(start, Ordering::Release);
+                return Some(index);
+            }
+        }
+        return None;
+    }
+
+    // tells readers we're still around without writing anything
+    pub fn assert_liveliness(&mut self) {
+        self.mapping
+            .top(6)
+            .store(clock_ns(libc::CLOCK_MONOTONIC), Ordering::Release);
+    }
+
+    pub fn release_cursor(&mut self, index: usize) {
+        self.mapping.cursor(index).store(0, Ordering::Release);
+    }
+
+    // Whether writing a message with a body of len bytes would overwrite one
+    // a cursor's reader hasn't read yet
+    pub fn is_full(&self, len: usize) -> bool {
+        let overwritten = self.overwrites(len);
+        if overwritten == 0 {
+            return false;
+        }
+        return (0..MAX_CURSORS).any(|index| {
+            let next = self.mapping.cursor(index).load(Ordering::Acquire);
+            return next != 0 && next <= overwritten;
+        });
+    }
+
+    // Room for up to num_messages messages with their bodies in data_bytes,
+    // none of them larger than max_bytes or with a head over max_head_bytes
+    pub fn new(
+        num_messages: usize,
+        data_bytes: usize,
+        max_bytes: usize,
+        max_head_bytes: usize,
+        memory: &MemoryOptions,
+    ) -> Result<SharedSegmentWriter, SocketError> {
+        if num_messages == 0 {
+            return Err(SocketError::new(
+                "Segment needs room for at least one message".to_string(),
+            ));
+        }
+        // rounded down so every slot stays aligned
+        let data_bytes = data_bytes & !7;
+        if slotsize(max_bytes) > data_bytes {
+            return Err(SocketError::new(format!(
+                "Segment of {}B has no room for a message of {}B",
+                data_bytes, max_bytes
+            )));
+        }
+
+        let head_bytes = headsize(num_messages, max_head_bytes);
+        let mut created = create_segment(head_bytes, data_bytes, memory.huge_pages, memory);
+        if let (true, Err(err)) = (memory.huge_pages, &created) {
+            println!("Segment falls back to normal pages: {}", err);
+            created = create_segment(head_bytes, data_bytes, false, memory);
+        }
+        let (fd, mapping, data_bytes) = created?;
+
+        // the rest of the header is already zeros, and zero sequence means
+        // unused
+        let mut rng = rand::thread_rng();
+        let id: u64 = rng.gen();
+        mapping.top(0).store(id, Ordering::Relaxed);
+        mapping.top(1).store(num_messages as u64, Ordering::Relaxed);
+        mapping.top(2).store(max_bytes as u64, Ordering::Relaxed);
+        mapping.top(8).store(data_bytes as u64, Ordering::Relaxed);
+        mapping
+            .top(9)
+            .store(max_head_bytes as u64, Ordering::Release);
+
+        return Ok(Self {
+            max_message_bytes: max_bytes as u64,
+            max_head_bytes: max_head_bytes as u64,
+            num_messages: num_messages as u64,
+            data_bytes: data_bytes as u64,
+            raw_fd: fd,
+            next_seq: 1,
+            mapping: mapping,
+            data_head: 0,
+            allocations: Default::default(),
+            memory: *memory,
+        });
+    }
+}
+
+// Creates and maps a zeroed memfd for a header of head_bytes and a data region
+// of data_bytes, or more once rounded up to whole huge pages. Returns how
+// large the data region ended up.
+fn create_segment(
+    head_bytes: usize,
+    data_bytes: usize,
+    huge_pages: bool,
+    memory: &MemoryOptions,
+) -> Result<(RawFd, Mapping, usize), SocketError> {
+    let flags = if huge_pages { libc::MFD_HUGETLB } else { 0 };
+    unsafe {
+        let fd = libc::memfd_create("segment\0".as_ptr() as *const i8, flags);
+        if fd == -1 {
+            return Err(SocketError::new(format!(
+                "Failed to construct memfd: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        let mut n_bytes = head_bytes + data_bytes;
+        if huge_pages {
+            // which is the huge page size on hugetlbfs
+            let mut stat: libc::stat = std::mem::zeroed();
+            if libc::fstat(fd, &mut stat) == -1 {
+                let err = std::io::Error::last_os_error();
+                libc::close(fd);
+                return Err(SocketError::new(format!("Failed to stat memfd: {}", err)));
+            }
+            let page = stat.st_blksize as usize;
+            n_bytes = n_bytes.div_ceil(page) * page;
+        }
+
+        // NOTE: ftruncate fills the file with zeros
+        let ret = libc::ftruncate(fd, n_bytes as i64);
+        if ret == -1 {
+            let err = std::io::Error::last_os_error();
+            libc::close(fd);
+            return Err(SocketError::new(format!(
+                "Failed to truncate mem to {} bytes: {}",
+                n_bytes, err
+            )));
+        }
+
+        // huge pages are reserved here, failing when there aren't enough
+        let mapping = match Mapping::new(fd, n_bytes, true, memory) {
+            Ok(mapping) => mapping,
+            Err(err) => {
+                libc::close(fd);
+                return Err(err);
+            }
+        };
+        return Ok((fd, mapping, n_bytes - head_bytes));
+    }
+}
+
+impl AsRawFd for SharedSegmentWriter {
+    fn as_raw_fd(&self) -> RawFd {
+        return self.raw_fd;
+    }
+}
+
+impl Drop for SharedSegmentWriter {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.raw_fd);
+        }
+    }
+}
+
+impl SharedSegmentReader {
+    // Maps a segment received from a publisher, takes ownership of fd. Only
+    // the last latched messages and those written after this point are read,
+    // or from the cursor on if the writer claimed one for us.
+    pub fn new(
+        fd: RawFd,
+        cursor: Option<usize>,
+        latched: usize,
+        memory: &MemoryOptions,
+    ) -> Result<SharedSegmentReader, SocketError> {
+        if cursor.is_some_and(|index| index >= MAX_CURSORS) {
+            unsafe {
+                libc::close(fd);
+            }
+            return Err(SocketError::new(format!(
+                "Cursor {} out of range",
+                cursor.unwrap()
+            )));
+        }
+
+        unsafe {
+            let mut stat: libc::stat = std::mem::zeroed();
+            if libc::fstat(fd, &mut stat) == -1 {
+                libc::close(fd);
                 return Err(SocketError::new(format!(
-                    "Failed to construct memfd: {}",
+                    "Failed to stat segment: {}",
                     std::io::Error::last_os_error()
                 )));
             }
 
-            // NOTE: ftruncate fills the file with zeros, zero sequence means unused
-            // so we don't need to initialize the header other than to fill the
-            // id field
-            let body_start = headsize(num_messages);
-            let n_bytes = max_bytes + headsize(num_messages);
-            {
-                let ret = libc::ftruncate(fd, n_bytes as i64);
-                if ret == -1 {
-                    libc::close(fd);
-                    return Err(SocketError::new(format!(
-                        "Failed to truncate mem to 4 bytes: {}",
-                        std::io::Error::last_os_error()
-                    )));
-                }
+            let n_bytes = stat.st_size as usize;
+            if n_bytes < headsize(0, 0) {
+                libc::close(fd);
+                return Err(SocketError::new(format!(
+                    "Segment of {}B is too small",
+                    n_bytes
+                )));
             }
 
-            // initialize header
-            let mut rng = rand::thread_rng();
-            let id: u64 = rng.gen();
-            {
-                let ptr: *const u64 = &id;
-                let ret = libc::pwrite(fd, ptr as *const libc::c_void, 8, 0);
-                if ret == -1 {
+            let mapping = match Mapping::new(fd, n_bytes, cursor.is_some(), memory) {
+                Ok(mapping) => mapping,
+                Err(err) => {
                     libc::close(fd);
-                    return Err(SocketError::new(format!(
-                        "Failed to write unique ID: {}",
-                        std::io::Error::last_os_error()
-                    )));
+                    return Err(err);
                 }
+            };
+
+            let num_messages = mapping.header(1);
+            let max_message_bytes = mapping.header(2);
+            let data_bytes = mapping.header(8);
+            let max_head_bytes = mapping.header(9);
+            // a meta and a head slot per message, with the head slot rounded
+            // up like headslot() does
+            let per_message = max_head_bytes
+                .checked_add((std::mem::size_of::<MessageMeta>() + SLOT_PREFIX + 7) as u64)
+                .map(|size| size & !7);
+            let expected = per_message
+                .and_then(|size| size.checked_mul(num_messages))
+                .and_then(|size| size.checked_add(headsize(0, 0) as u64))
+                .and_then(|size| size.checked_add(data_bytes));
+            if num_messages == 0 || expected != Some(n_bytes as u64) {
+                libc::close(fd);
+                return Err(SocketError::new(format!(
+                    "Segment of {}B doesn't fit {} messages in {}B",
+                    n_bytes, num_messages, data_bytes
+                )));
             }
 
+            let latched = (latched as u64).min(num_messages);
+            let reclaimed = mapping.top(7).load(Ordering::Acquire);
+            let mut next_seq = (mapping.latest_seq(num_messages as usize) + 1)
+                .saturating_sub(latched)
+                .max(reclaimed + 1);
+            if let Some(index) = cursor {
+                next_seq = mapping.cursor(index).load(Ordering::Acquire);
+            }
             return Ok(Self {
-                max_message_bytes: max_bytes as u64,
-                num_messages: num_messages as u64,
+                num_messages: num_messages,
+                max_message_bytes: max_message_bytes,
+                max_head_bytes: max_head_bytes,
                 raw_fd: fd,
-                next_seq: 1,
+                next_seq: next_seq,
+                mapping: mapping,
+                stats: Default::default(),
+                cursor: cursor,
+                room: None,
+                lifespan: None,
+                heads_only: false,
             });
         }
     }
+
+    // Returns the next unread message, if one has been written. Messages that
+    // were overwritten before we got to them are skipped, and counted in
+    // stats().
+    pub fn read(&mut self) -> Option<Sample> {
+        let sample = self.read_next();
+        self.store_cursor(self.next_seq);
+        return sample;
+    }
+
+    // Moves our cursor to next, waking the writer if that makes room for it
+    fn store_cursor(&self, next: u64) {
+        if let Some(index) = self.cursor {
+            let previous = self.mapping.cursor(index).swap(next, Ordering::AcqRel);
+            if let (true, Some(room)) = (previous != next, &self.room) {
+                if let Err(err) = room.wake() {
+                    println!("Failed to wake segment writer: {}", err);
+                }
+            }
+        }
+    }
+
+    fn read_next(&mut self) -> Option<Sample> {
+        loop {
+            // the writer made room for newer messages over these
+            let reclaimed = self.mapping.top(7).load(Ordering::Acquire);
+            if reclaimed >= self.next_seq {
+                self.stats.dropped += reclaimed + 1 - self.next_seq;
+                self.next_seq = reclaimed + 1;
+                continue;
+            }
+
+            let index = ((self.next_seq - 1) % self.num_messages) as usize;
+            let meta = self.mapping.meta(index);
+            let seq = meta.seq.load(Ordering::Acquire);
+
+            if seq.unsigned_abs() > self.next_seq {
+                // lapped, jump to the oldest message still in the segment
+                let latest = self.mapping.latest_seq(self.num_messages as usize);
+                let oldest = self
+                    .next_seq
+                    .max((latest + 1).saturating_sub(self.num_messages));
+                self.stats.dropped += oldest - self.next_seq;
+                self.next_seq = oldest;
+                continue;
+            }
+            if seq != self.next_seq as i64 {
+                // not written yet, or still in flight
+                return None;
+            }
+
+            if meta.offset.load(Ordering::Relaxed) == SKIPPED {
+                fence(Ordering::Acquire);
+                if meta.seq.load(Ordering::Relaxed) != seq {
+                    continue;
+                }
+                // sent outside the segment (see skip()), stop here so the
+                // caller can fit it in, see pass_skipped()
+                return None;
+            }
+
+            if let Some(lifespan) = self.lifespan {
+                let stamp = meta.stamp.load(Ordering::Relaxed);
+                fence(Ordering::Acquire);
+                if meta.seq.load(Ordering::Relaxed) != seq {
+                    // overwritten meanwhile, see what's there now
+                    continue;
+                }
+                let age = clock_ns(libc::CLOCK_MONOTONIC).saturating_sub(stamp);
+                if age > lifespan.as_nanos() as u64 {
+                    self.stats.expired += 1;
+                    self.next_seq += 1;
+                    continue;
+                }
+            }
+
+            let message = self.copy_message(index, meta);
+
+            // if the writer came back around while we were copying, or made
+            // room over it, then what we have is garbage
+            fence(Ordering::Acquire);
+            let after = meta.seq.load(Ordering::Relaxed);
+            let reclaimed = self.mapping.top(7).load(Ordering::Relaxed);
+            self.next_seq += 1;
+            if after != seq || reclaimed >= seq as u64 {
+                self.stats.torn += 1;
+                continue;
+            }
+
+            match message {
+                Some((head_crc, crc, head_slot, body)) => {
+                    if CRC64.checksum(&head_slot) != head_crc
+                        || (!self.heads_only && CRC64.checksum(&body) != crc)
+                    {
+                        self.stats.crc_failed += 1;
+                        continue;
+                    }
+                    self.stats.received += 1;
+                    let word = |i: usize| {
+                        u64::from_le_bytes(head_slot[i * 8..i * 8 + 8].try_into().unwrap())
+                    };
+                    return Some(Sample {
+                        head: head_slot[SLOT_PREFIX..].to_vec(),
+                        body: body,
+                        info: SampleInfo {
+                            sequence: seq as u64,
+                            monotonic_ns: word(2),
+                            realtime_ns: word(3),
+                            publisher_id: self.mapping.header(3),
+                            topic_id: self.mapping.header(4),
+                        },
+                    });
+                }
+                None => {
+                    self.stats.torn += 1;
+                    continue;
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> SubscriptionStats {
+        return self.stats;
+    }
+
+    // Carries on where previous left off, when this maps the segment its
+    // writer grew into (see SharedSegmentWriter::grow())
+    pub fn resume(&mut self, previous: &SharedSegmentReader) {
+        self.next_seq = previous.next_seq;
+        self.stats = previous.stats;
+        self.lifespan = previous.lifespan;
+        self.heads_only = previous.heads_only;
+        self.room = previous.room.clone();
+        self.store_cursor(self.next_seq);
+    }
+
+    // sequence of the next message to be read
+    pub fn next_sequence(&self) -> u64 {
+        return self.next_seq;
+    }
+
+    // whether read() has stopped at a message sent outside the segment
+    pub fn is_skipped(&self) -> bool {
+        let index = ((self.next_seq - 1) % self.num_messages) as usize;
+        let meta = self.mapping.meta(index);
+        let seq = meta.seq.load(Ordering::Acquire);
+        let offset = meta.offset.load(Ordering::Relaxed);
+        fence(Ordering::Acquire);
+        return seq == self.next_seq as i64
+            && offset == SKIPPED
+            && meta.seq.load(Ordering::Relaxed) == seq;
+    }
+
+    // Moves on from the message read() stopped at, once the caller has it or
+    // knows it won't get it
+    pub fn pass_skipped(&mut self) {
+        self.next_seq += 1;
+        self.store_cursor(self.next_seq);
+    }
+
+    // Wakes room whenever our cursor moves, for a writer that waits on it
+    // rather than polling the cursor for room to write
+    pub fn set_room(&mut self, room: Arc<Futex>) {
+        self.room = Some(room);
+    }
+
+    // from now on messages older than lifespan are skipped and counted as
+    // expired rather than read
+    pub fn set_lifespan(&mut self, lifespan: Option<Duration>) {
+        self.lifespan = lifespan;
+    }
+
+    // from now on messages are read with empty bodies, without touching
+    // theirs in the segment
+    pub fn set_heads_only(&mut self, heads_only: bool) {
+        self.heads_only = heads_only;
+    }
+
+    pub fn publisher_id(&self) -> u64 {
+        return self.mapping.header(3);
+    }
+
+    // CLOCK_MONOTONIC ns of the latest write, 0 before the first
+    pub fn last_written_ns(&self) -> u64 {
+        return self.mapping.top(5).load(Ordering::Acquire);
+    }
+
+    // CLOCK_MONOTONIC ns the writer last showed it's alive, by writing or
+    // otherwise
+    pub fn last_asserted_ns(&self) -> u64 {
+        return self
+            .last_written_ns()
+            .max(self.mapping.top(6).load(Ordering::Acquire));
+    }
+
+    // Copies out a message's head slot, and its body unless heads only, with
+    // the crcs they were written with. None when the lengths read don't make
+    // sense (which happens when torn).
+    fn copy_message(
+        &self,
+        index: usize,
+        meta: &MessageMeta,
+    ) -> Option<(u64, u64, Vec<u8>, Vec<u8>)> {
+        let head_crc = meta.head_crc.load(Ordering::Relaxed);
+        let crc = meta.crc.load(Ordering::Relaxed);
+        let offset = meta.offset.load(Ordering::Relaxed) as usize;
+        let length = meta.length.load(Ordering::Relaxed) as usize;
+        let num_messages = self.num_messages as usize;
+        let max_head_bytes = self.max_head_bytes as usize;
+        let start = head_offset(num_messages, max_head_bytes, index);
+
+        let mut prefix: [u8; SLOT_PREFIX] = [0; SLOT_PREFIX];
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.mapping.ptr.add(start),
+                prefix.as_mut_ptr(),
+                SLOT_PREFIX,
+            );
+        }
+        let head_len = u64::from_le_bytes(prefix[0..8].try_into().unwrap());
+        let body_len = u64::from_le_bytes(prefix[8..16].try_into().unwrap());
+        if head_len > self.max_head_bytes
+            || body_len != length as u64
+            || head_len + body_len > self.max_message_bytes
+            || offset < headsize(num_messages, max_head_bytes)
+            || offset.saturating_add(length) > self.mapping.len
+        {
+            return None;
+        }
+
+        let mut head_slot: Vec<u8> = vec![0; SLOT_PREFIX + head_len as usize];
+        let mut body: Vec<u8> = Vec::new();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.mapping.ptr.add(start),
+                head_slot.as_mut_ptr(),
+                head_slot.len(),
+            );
+            if !self.heads_only {
+                body.resize(length, 0);
+                std::ptr::copy_nonoverlapping(
+                    self.mapping.ptr.add(offset),
+                    body.as_mut_ptr(),
+                    length,
+                );
+            }
+        }
+        return Some((head_crc, crc, head_slot, body));
+    }
+}
+
+// Writes a message too large for any segment into a memfd of its own, laid
+// out like a head slot with the body right after it. It's sealed so receivers
+// can map it knowing it won't change under them.
+pub(crate) fn write_sealed(
+    head: &[u8],
+    body: &[u8],
+    monotonic_ns: u64,
+    realtime_ns: u64,
+) -> Result<RawFd, SocketError> {
+    let mut prefix: [u8; SLOT_PREFIX] = [0; SLOT_PREFIX];
+    prefix[0..8].copy_from_slice(&(head.len() as u64).to_le_bytes());
+    prefix[8..16].copy_from_slice(&(body.len() as u64).to_le_bytes());
+    prefix[16..24].copy_from_slice(&monotonic_ns.to_le_bytes());
+    prefix[24..32].copy_from_slice(&realtime_ns.to_le_bytes());
+
+    unsafe {
+        let fd = libc::memfd_create(
+            c"sample".as_ptr(),
+            libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+        );
+        if fd == -1 {
+            return Err(SocketError::new(format!(
+                "Failed to construct memfd: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        let mut file = std::fs::File::from_raw_fd(fd);
+        for part in [&prefix[..], head, body] {
+            if let Err(err) = file.write_all(part) {
+                return Err(SocketError::new(format!(
+                    "Failed to write sample to memfd: {}",
+                    err
+                )));
+            }
+        }
+
+        let fd = file.into_raw_fd();
+        let seals =
+            libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+        if libc::fcntl(fd, libc::F_ADD_SEALS, seals) == -1 {
+            let err = std::io::Error::last_os_error();
+            libc::close(fd);
+            return Err(SocketError::new(format!("Failed to seal memfd: {}", err)));
+        }
+        return Ok(fd);
+    }
+}
+
+// Reads a message from a memfd written by write_sealed(), takes ownership of
+// fd. Only the timestamps of the SampleInfo are filled in.
+pub(crate) fn read_sealed(fd: RawFd, heads_only: bool) -> Result<Sample, SocketError> {
+    unsafe {
+        let seals = libc::fcntl(fd, libc::F_GET_SEALS);
+        let required = libc::F_SEAL_SHRINK | libc::F_SEAL_WRITE;
+        if seals == -1 || seals & required != required {
+            libc::close(fd);
+            return Err(SocketError::new(
+                "Oversized sample memfd isn't sealed".to_string(),
+            ));
+        }
+
+        let mut stat: libc::stat = std::mem::zeroed();
+        if libc::fstat(fd, &mut stat) == -1 {
+            let err = std::io::Error::last_os_error();
+            libc::close(fd);
+            return Err(SocketError::new(format!(
+                "Failed to stat sample memfd: {}",
+                err
+            )));
+        }
+        let n_bytes = stat.st_size as usize;
+        if n_bytes < SLOT_PREFIX {
+            libc::close(fd);
+            return Err(SocketError::new(format!(
+                "Sample memfd of {}B is too small",
+                n_bytes
+            )));
+        }
+
+        // the mapping outlives the fd
+        let mapping = Mapping::new(fd, n_bytes, false, &MemoryOptions::default());
+        libc::close(fd);
+        let mapping = mapping?;
+
+        let slot = std::slice::from_raw_parts(mapping.ptr as *const u8, n_bytes);
+        let word = |i: usize| u64::from_le_bytes(slot[i * 8..i * 8 + 8].try_into().unwrap());
+        let head_len = word(0) as usize;
+        if head_len.saturating_add(word(1) as usize) != n_bytes - SLOT_PREFIX {
+            return Err(SocketError::new(format!(
+                "Sample memfd of {}B doesn't match its lengths",
+                n_bytes
+            )));
+        }
+        // the body's pages are only touched when copied
+        let mut body: Vec<u8> = Vec::new();
+        if !heads_only {
+            body = slot[SLOT_PREFIX + head_len..].to_vec();
+        }
+        return Ok(Sample {
+            head: slot[SLOT_PREFIX..SLOT_PREFIX + head_len].to_vec(),
+            body: body,
+            info: SampleInfo {
+                monotonic_ns: word(2),
+                realtime_ns: word(3),
+                ..Default::default()
+            },
+        });
+    }
+}
+
+impl Drop for SharedSegmentReader {
+    fn drop(&mut self) {
+        // the cursor stays claimed until the writer hears we're gone
+        self.store_cursor(CURSOR_DETACHED);
+        unsafe {
+            libc::close(self.raw_fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writer(num_messages: usize, data_bytes: usize) -> SharedSegmentWriter {
+        return SharedSegmentWriter::new(num_messages, data_bytes, 64, 16, &Default::default())
+            .unwrap();
+    }
+
+    fn write(writer: &mut SharedSegmentWriter, body: &[u8]) {
+        let monotonic_ns = clock_ns(libc::CLOCK_MONOTONIC);
+        let realtime_ns = clock_ns(libc::CLOCK_REALTIME);
+        writer.write(b"h", body, monotonic_ns, realtime_ns).unwrap();
+    }
+
+    fn reader(writer: &SharedSegmentWriter) -> SharedSegmentReader {
+        let fd = unsafe { libc::dup(writer.as_raw_fd()) };
+        return SharedSegmentReader::new(fd, None, 0, &Default::default()).unwrap();
+    }
+
+    fn read_all(reader: &mut SharedSegmentReader) -> Vec<u64> {
+        let mut seqs: Vec<u64> = Vec::new();
+        while let Some(sample) = reader.read() {
+            seqs.push(sample.info.sequence);
+        }
+        return seqs;
+    }
+
+    fn spans(writer: &SharedSegmentWriter) -> Vec<(u64, u64, u64)> {
+        return writer
+            .allocations
+            .iter()
+            .map(|allocation| (allocation.seq, allocation.start, allocation.end))
+            .collect();
+    }
+
+    #[test]
+    fn fills_without_overwriting() {
+        let mut writer = writer(8, 128);
+        let mut reader = reader(&writer);
+        for _ in 0..4 {
+            assert_eq!(writer.overwrites(32), 0);
+            write(&mut writer, &[1; 32]);
+        }
+        assert_eq!(
+            spans(&writer),
+            [(1, 0, 32), (2, 32, 64), (3, 64, 96), (4, 96, 128)]
+        );
+        assert_eq!(read_all(&mut reader), [1, 2, 3, 4]);
+        assert_eq!(reader.stats().lost(), 0);
+    }
+
+    #[test]
+    fn wrapping_overwrites_the_oldest() {
+        let mut writer = writer(8, 128);
+        let mut reader = reader(&writer);
+        for _ in 0..3 {
+            write(&mut writer, &[1; 40]);
+        }
+        // 8 bytes left at the end, given up to wrap
+        assert_eq!(writer.overwrites(16), 1);
+        write(&mut writer, &[2; 16]);
+        assert_eq!(spans(&writer), [(2, 40, 80), (3, 80, 120), (4, 0, 16)]);
+        // up to and over the second
+        assert_eq!(writer.overwrites(32), 2);
+        write(&mut writer, &[3; 32]);
+        assert_eq!(read_all(&mut reader), [3, 4, 5]);
+        let stats = reader.stats();
+        assert_eq!((stats.dropped, stats.crc_failed, stats.torn), (2, 0, 0));
+    }
+
+    #[test]
+    fn reusing_head_slots_overwrites() {
+        let mut writer = writer(2, 128);
+        let mut reader = reader(&writer);
+        write(&mut writer, b"");
+        write(&mut writer, &[1; 8]);
+        // room in the data region, but not for another head
+        assert_eq!(writer.overwrites(8), 1);
+        write(&mut writer, &[2; 8]);
+        assert_eq!(read_all(&mut reader), [2, 3]);
+        assert_eq!(reader.stats().dropped, 1);
+    }
+
+    #[test]
+    fn empty_bodies_take_no_room() {
+        let mut writer = writer(4, 64);
+        for _ in 0..10 {
+            assert!(writer.overwrites(0) <= writer.next_seq.saturating_sub(4));
+            write(&mut writer, b"");
+        }
+        assert!(writer.allocations.len() <= 4);
+        assert!(writer.allocations.iter().all(|a| a.start == a.end));
+    }
+
+    #[test]
+    fn empty_body_at_the_head_doesnt_hide_what_follows() {
+        let mut writer = writer(8, 128);
+        let mut reader = reader(&writer);
+        write(&mut writer, &[1; 48]);
+        write(&mut writer, b"");
+        write(&mut writer, &[3; 48]);
+        write(&mut writer, &[4; 48]);
+        assert_eq!(spans(&writer), [(2, 48, 48), (3, 48, 96), (4, 0, 48)]);
+        // right over the third, and the empty second with it
+        assert_eq!(writer.overwrites(8), 3);
+        write(&mut writer, &[5; 8]);
+        assert_eq!(read_all(&mut reader), [4, 5]);
+        let stats = reader.stats();
+        assert_eq!((stats.dropped, stats.crc_failed, stats.torn), (3, 0, 0));
+    }
+
+    #[test]
+    fn moving_the_cursor_wakes_the_room() {
+        let mut writer = writer(4, 128);
+        let cursor = writer.claim_cursor(0);
+        let fd = unsafe { libc::dup(writer.as_raw_fd()) };
+        let mut reader = SharedSegmentReader::new(fd, cursor, 0, &Default::default()).unwrap();
+        let room = Arc::new(Futex::new().unwrap());
+        reader.set_room(room.clone());
+        for _ in 0..4 {
+            write(&mut writer, &[1; 32]);
+        }
+        assert!(writer.is_full(32));
+
+        let seen = room.value();
+        assert!(reader.read().is_some());
+        assert_ne!(room.value(), seen);
+        assert!(!writer.is_full(32));
+
+        // nothing new read, nothing to wake the writer for
+        while reader.read().is_some() {}
+        let seen = room.value();
+        assert!(reader.read().is_none());
+        assert_eq!(room.value(), seen);
+
+        // nor held up by us any longer
+        drop(reader);
+        assert_ne!(room.value(), seen);
+    }
 }
@@ -0,0 +1,66 @@
+use crate::errors::SocketError;
+
+pub struct SignalFd {
+    raw_fd: libc::c_int,
+}
+
+impl SignalFd {
+    // Blocks the given signals for the calling thread (and any thread spawned
+    // from it afterwards) and routes them to a file descriptor instead
+    pub fn new(signals: &[libc::c_int]) -> Result<SignalFd, SocketError> {
+        unsafe {
+            let mut mask: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut mask);
+            for signal in signals {
+                libc::sigaddset(&mut mask, *signal);
+            }
+
+            let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut());
+            if ret != 0 {
+                return Err(SocketError::new(format!(
+                    "Failed to block signals: {}",
+                    std::io::Error::from_raw_os_error(ret)
+                )));
+            }
+
+            let fd = libc::signalfd(-1, &mask, libc::SFD_CLOEXEC);
+            if fd == -1 {
+                return Err(SocketError::new(format!(
+                    "Failed to construct signalfd: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+            return Ok(SignalFd { raw_fd: fd });
+        }
+    }
+
+    // returns the number of the signal that was received
+    pub fn read(&self) -> Result<u32, SocketError> {
+        unsafe {
+            let mut info: libc::signalfd_siginfo = std::mem::zeroed();
+            let ptr: *mut libc::signalfd_siginfo = &mut info;
+            let size = std::mem::size_of::<libc::signalfd_siginfo>();
+            let ret = libc::read(self.raw_fd, ptr as *mut libc::c_void, size);
+            if ret == -1 {
+                return Err(SocketError::new(format!(
+                    "Failed to read signal: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+
+            return Ok(info.ssi_signo);
+        }
+    }
+
+    pub fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        return self.raw_fd;
+    }
+}
+
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.raw_fd);
+        }
+    }
+}
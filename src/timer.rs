@@ -0,0 +1,94 @@
+use crate::errors::SocketError;
+use std::time::Duration;
+
+// A CLOCK_MONOTONIC timerfd, readable once it has expired
+pub struct TimerFd {
+    raw_fd: libc::c_int,
+}
+
+fn timespec(duration: Duration) -> libc::timespec {
+    return libc::timespec {
+        tv_sec: duration.as_secs() as libc::time_t,
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
+    };
+}
+
+impl TimerFd {
+    pub fn new() -> Result<TimerFd, SocketError> {
+        unsafe {
+            let fd = libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_CLOEXEC | libc::TFD_NONBLOCK,
+            );
+            if fd == -1 {
+                return Err(SocketError::new(format!(
+                    "Failed to construct timerfd: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+            return Ok(TimerFd { raw_fd: fd });
+        }
+    }
+
+    pub fn dup(&self) -> Result<TimerFd, SocketError> {
+        unsafe {
+            let fd = libc::dup(self.raw_fd);
+            if fd == -1 {
+                return Err(SocketError::new(format!(
+                    "Failed to construct timerfd: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+            return Ok(TimerFd { raw_fd: fd });
+        }
+    }
+
+    // Expires after initial, then every interval unless it's zero. A zero
+    // initial disarms the timer.
+    pub fn set(&self, initial: Duration, interval: Duration) -> Result<(), SocketError> {
+        let spec = libc::itimerspec {
+            it_interval: timespec(interval),
+            it_value: timespec(initial),
+        };
+        unsafe {
+            let ret = libc::timerfd_settime(self.raw_fd, 0, &spec, std::ptr::null_mut());
+            if ret == -1 {
+                return Err(SocketError::new(format!(
+                    "Failed to set timer: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+        }
+        return Ok(());
+    }
+
+    // returns how many times the timer expired since the last read, 0 if it
+    // hasn't
+    pub fn read(&self) -> Result<u64, SocketError> {
+        unsafe {
+            let mut value: u64 = 0;
+            let ptr: *mut u64 = &mut value;
+            let ret = libc::read(self.raw_fd, ptr as *mut libc::c_void, 8);
+            if ret == -1 {
+                let err = std::io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::EAGAIN) {
+                    return Ok(0);
+                }
+                return Err(SocketError::new(format!("Failed to read timer: {}", err)));
+            }
+            return Ok(value);
+        }
+    }
+
+    pub fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        return self.raw_fd;
+    }
+}
+
+impl Drop for TimerFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.raw_fd);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use crate::codec::Codec;
+#[cfg(any(feature = "bincode", feature = "cbor", feature = "json"))]
+use crate::codec::DefaultCodec;
+use crate::errors::SocketError;
+use crate::node::{Node, SubscriptionConfig};
+use crate::publisher::Publisher;
+use crate::shared_segment::Sample;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::Arc;
+
+// announced as the body type, typed samples have no head
+fn body_type_name<T, C: Codec>() -> String {
+    return format!("{}:{}", C::NAME, std::any::type_name::<T>());
+}
+
+impl<T: Serialize> Publisher<T> {
+    pub fn publish(&self, value: &T) -> Result<(), SocketError> {
+        let body = self.encode(value)?;
+        return self.publish_bytes(&[], &body);
+    }
+}
+
+impl Node {
+    // Announces topic as carrying T, encoded with the default codec
+    #[cfg(any(feature = "bincode", feature = "cbor", feature = "json"))]
+    pub fn advertise<T: Serialize>(&self, topic: &str) -> Result<Publisher<T>, SocketError> {
+        return self.advertise_with::<T, DefaultCodec>(topic);
+    }
+
+    pub fn advertise_with<T: Serialize, C: Codec>(
+        &self,
+        topic: &str,
+    ) -> Result<Publisher<T>, SocketError> {
+        self.announce(topic, "", &body_type_name::<T, C>(), b"")?;
+        return Ok(Publisher::typed(
+            topic,
+            Arc::downgrade(&self.state),
+            C::encode::<T>,
+        ));
+    }
+
+    // Subscribes to topic, which has to be published as T with the default
+    // codec. Fails if a known publisher announced anything else, publishers
+    // of other types showing up later are ignored.
+    #[cfg(any(feature = "bincode", feature = "cbor", feature = "json"))]
+    pub fn subscribe_typed<T, F>(&self, topic: &str, cb: F) -> Result<(), SocketError>
+    where
+        T: DeserializeOwned,
+        F: Fn(T) + Send + 'static,
+    {
+        return self.subscribe_typed_with::<T, DefaultCodec, F>(topic, cb);
+    }
+
+    pub fn subscribe_typed_with<T, C, F>(&self, topic: &str, cb: F) -> Result<(), SocketError>
+    where
+        T: DeserializeOwned,
+        C: Codec,
+        F: Fn(T) + Send + 'static,
+    {
+        let name = topic.to_string();
+        let callback = move |sample: &Sample| match C::decode::<T>(&sample.body) {
+            Ok(value) => cb(value),
+            Err(err) => println!("Dropping sample on {}: {}", name, err),
+        };
+        let type_names = (String::new(), body_type_name::<T, C>());
+        self.add_subscription(
+            topic,
+            Some(type_names),
+            &SubscriptionConfig::default(),
+            Some(Box::new(callback)),
+        )?;
+        return Ok(());
+    }
+}
+
+#[cfg(all(test, any(feature = "bincode", feature = "cbor", feature = "json")))]
+mod tests {
+    use super::*;
+    use crate::node::NodeConfig;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    type Message = (u32, String);
+
+    fn node(name: &str) -> Node {
+        return Node::new(&NodeConfig {
+            name: name.to_string(),
+            max_nodes: 16,
+            handle_signals: false,
+        })
+        .unwrap();
+    }
+
+    // subscribes to topic with codec C, handing over what's decoded
+    fn subscribe<C: Codec>(node: &Node, topic: &str) -> mpsc::Receiver<Message> {
+        let (sender, receiver) = mpsc::channel();
+        node.subscribe_typed_with::<Message, C, _>(topic, move |value| {
+            let _ = sender.send(value);
+        })
+        .unwrap();
+        return receiver;
+    }
+
+    // publishes until something is received, or gives up after a few seconds
+    fn first_value(publish: impl Fn(), receiver: &mpsc::Receiver<Message>) -> Option<Message> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            publish();
+            if let Ok(value) = receiver.recv_timeout(Duration::from_millis(10)) {
+                return Some(value);
+            }
+        }
+        return None;
+    }
+
+    fn publish_and_take<C: Codec>(name: &str) {
+        let a = node(&format!("{}_a", name));
+        let b = node(&format!("{}_b", name));
+        let topic = format!("/{}", name);
+        let publisher = a.advertise_with::<Message, C>(&topic).unwrap();
+        let receiver = subscribe::<C>(&b, &topic);
+        let value = (7, "seven".to_string());
+        let publish = || publisher.publish(&value).unwrap();
+        assert_eq!(first_value(publish, &receiver), Some(value.clone()));
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_publish_and_take() {
+        publish_and_take::<crate::codec::Bincode>("typed_bincode");
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_publish_and_take() {
+        publish_and_take::<crate::codec::Cbor>("typed_cbor");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_publish_and_take() {
+        publish_and_take::<crate::codec::Json>("typed_json");
+    }
+
+    #[test]
+    fn undecodable_samples_are_dropped() {
+        let a = node("typed_garbage_a");
+        let b = node("typed_garbage_b");
+        let type_name = body_type_name::<Message, DefaultCodec>();
+        a.announce("/typed_garbage", "", &type_name, b"").unwrap();
+        let receiver = subscribe::<DefaultCodec>(&b, "/typed_garbage");
+        let value = (7, "seven".to_string());
+        let body = DefaultCodec::encode(&value).unwrap();
+
+        // each good one comes through all the same
+        let publish = || {
+            a.publish("/typed_garbage", b"", &[0xff; 3]).unwrap();
+            a.publish("/typed_garbage", b"", &body).unwrap();
+        };
+        assert_eq!(first_value(publish, &receiver), Some(value.clone()));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(receiver.try_iter().all(|received| received == value));
+    }
+}
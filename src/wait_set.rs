@@ -0,0 +1,184 @@
+use crate::epoll::{DescribedInput, Epoll};
+use crate::errors::SocketError;
+use crate::event::EventFd;
+use crate::node::{Node, Subscription};
+use crate::timer::TimerFd;
+use std::time::Duration;
+
+// Waits on subscriptions, timers, guard conditions and nodes shutting down
+// all at once, for loops that take() samples themselves rather than have
+// callbacks. Each is added under an id, which wait() returns once it's ready.
+pub struct WaitSet {
+    epoll: Epoll,
+    next_id: u64,
+    // added with add_shutdown()
+    shutdowns: Vec<u64>,
+    // of those, the ones that have shut down
+    stopped: Vec<u64>,
+}
+
+impl WaitSet {
+    pub fn new() -> Result<WaitSet, SocketError> {
+        return Ok(WaitSet {
+            epoll: Epoll::new()?,
+            next_id: 0,
+            shutdowns: Vec::new(),
+            stopped: Vec::new(),
+        });
+    }
+
+    // Ready once something has been published to sub since the last wait(),
+    // take() until there's nothing left
+    pub fn add_subscription(&mut self, sub: &Subscription) -> Result<u64, SocketError> {
+        let id = self.take_id();
+        self.epoll.add_event(id, sub.event().dup()?)?;
+        return Ok(id);
+    }
+
+    // ready once timer has expired since the last wait()
+    pub fn add_timer(&mut self, timer: &TimerFd) -> Result<u64, SocketError> {
+        let id = self.take_id();
+        self.epoll.add_timer(id, timer.dup()?)?;
+        return Ok(id);
+    }
+
+    // Ready once guard has been incremented since the last wait(), which
+    // resets it. For waking a waiting loop from another thread.
+    pub fn add_guard_condition(&mut self, guard: &EventFd) -> Result<u64, SocketError> {
+        let id = self.take_id();
+        self.epoll.add_event(id, guard.dup()?)?;
+        return Ok(id);
+    }
+
+    // ready from when node or its context has shut down on, whether through
+    // shutdown() or a signal
+    pub fn add_shutdown(&mut self, node: &Node) -> Result<u64, SocketError> {
+        let id = self.take_id();
+        self.epoll.add_event(id, node.stopped()?)?;
+        self.shutdowns.push(id);
+        return Ok(id);
+    }
+
+    // Blocks until something is ready or timeout passes, None waits for as
+    // long as it takes. Returns the ids of everything ready, in the order
+    // they were added, nothing if it timed out.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<Vec<u64>, SocketError> {
+        let mut ready: Vec<u64> = self.stopped.clone();
+        let mut timeout = if ready.is_empty() {
+            timeout
+        } else {
+            Some(Duration::ZERO)
+        };
+        // after the first, collect whatever else is ready without waiting
+        while let Some(input) = self.epoll.wait(timeout)? {
+            match input {
+                DescribedInput::Event(_, id) => {
+                    if self.shutdowns.contains(&id) && !self.stopped.contains(&id) {
+                        self.stopped.push(id);
+                    }
+                    ready.push(id);
+                }
+                DescribedInput::Timer(_, id) => {
+                    ready.push(id);
+                }
+                _ => {}
+            }
+            timeout = Some(Duration::ZERO);
+        }
+        ready.sort();
+        ready.dedup();
+        return Ok(ready);
+    }
+
+    fn take_id(&mut self) -> u64 {
+        self.next_id += 1;
+        return self.next_id - 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::NodeConfig;
+    use std::time::Instant;
+
+    fn node(name: &str) -> Node {
+        return Node::new(&NodeConfig {
+            name: name.to_string(),
+            max_nodes: 16,
+            handle_signals: false,
+        })
+        .unwrap();
+    }
+
+    const SHORT: Option<Duration> = Some(Duration::from_millis(10));
+
+    #[test]
+    fn published_subscriptions_are_ready() {
+        let a = node("wait_set_a");
+        let b = node("wait_set_b");
+        a.announce("/wait_set", "", "X", b"").unwrap();
+        let sub = b.subscription("/wait_set").unwrap();
+        let mut wait_set = WaitSet::new().unwrap();
+        let timer = wait_set.add_timer(&TimerFd::new().unwrap()).unwrap();
+        let id = wait_set.add_subscription(&sub).unwrap();
+        assert_ne!(timer, id);
+
+        let start = Instant::now();
+        let ready = loop {
+            a.publish("/wait_set", b"", b"").unwrap();
+            let ready = wait_set.wait(SHORT).unwrap();
+            if !ready.is_empty() || start.elapsed() > Duration::from_secs(5) {
+                break ready;
+            }
+        };
+        assert_eq!(ready, [id]);
+        assert!(sub.take().is_some());
+    }
+
+    #[test]
+    fn expired_timers_are_ready() {
+        let timer = TimerFd::new().unwrap();
+        let mut wait_set = WaitSet::new().unwrap();
+        let id = wait_set.add_timer(&timer).unwrap();
+        timer
+            .set(Duration::from_millis(20), Duration::ZERO)
+            .unwrap();
+        assert_eq!(wait_set.wait(None).unwrap(), [id]);
+    }
+
+    #[test]
+    fn guard_conditions_are_ready_once_per_increment() {
+        let guard = EventFd::new().unwrap();
+        let mut wait_set = WaitSet::new().unwrap();
+        let id = wait_set.add_guard_condition(&guard).unwrap();
+        assert!(wait_set.wait(SHORT).unwrap().is_empty());
+        guard.incr().unwrap();
+        assert_eq!(wait_set.wait(SHORT).unwrap(), [id]);
+        assert!(wait_set.wait(SHORT).unwrap().is_empty());
+    }
+
+    #[test]
+    fn shutdown_stays_ready() {
+        let mut a = node("wait_set_stop");
+        let mut wait_set = WaitSet::new().unwrap();
+        let id = wait_set.add_shutdown(&a).unwrap();
+        assert!(wait_set.wait(SHORT).unwrap().is_empty());
+        a.shutdown().unwrap();
+        for _ in 0..3 {
+            assert_eq!(wait_set.wait(SHORT).unwrap(), [id]);
+        }
+    }
+
+    #[test]
+    fn timing_out_returns_nothing() {
+        let mut wait_set = WaitSet::new().unwrap();
+        wait_set.add_timer(&TimerFd::new().unwrap()).unwrap();
+        let start = Instant::now();
+        assert!(wait_set
+            .wait(Some(Duration::from_millis(20)))
+            .unwrap()
+            .is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}